@@ -1,43 +1,135 @@
-use crate::instr::{CarbonASMProgram, CarbonConds, CarbonInstrVariants, CarbonOperand};
+use std::collections::HashMap;
+
+use crate::diagnostic::{Diagnostic, Span, Spanned};
+use crate::frontend::parser::{ConstantDef, LabelMap};
+use crate::frontend::sections::Section;
+use crate::instr::{CarbonASMProgram, CarbonConds, CarbonOperand};
+use crate::isa::Target;
+
+// the most a target can have; jump addresses and page selects are both five bits
+pub const PAGE_SIZE: usize = 32;
+pub const PAGE_COUNT: usize = 32;
 
 struct PageWriter {
-    current_page: usize,
+    page_size: usize,
+    page_count: usize,
+    // None after a page marker that doesn't exist; writes are dropped until the next one
+    current_page: Option<usize>,
     current_page_ptr: usize,
     pages: Vec<Vec<PageOutput>>,
+    // span of the node that wrote each byte, for reporting overlapping writes
+    owners: Vec<Vec<Option<Span>>>,
     comments: Vec<(String, usize)>,
+    span: Span,
+    // first node that ran past the end of the current page, and by how many bytes
+    overflow: Option<(Span, usize)>,
+    overlapped: bool,
+    diags: Vec<Diagnostic>,
 }
 
 impl PageWriter {
-    pub fn new() -> PageWriter {
+    pub fn new(target: &Target) -> PageWriter {
+        let (page_size, page_count) = (target.page_size, target.page_count);
         PageWriter {
-            current_page: 0,
+            page_size,
+            page_count,
+            current_page: Some(0),
             current_page_ptr: 0,
-            pages: vec![vec![PageOutput::Lit(0); 32]; 32],
+            pages: vec![vec![PageOutput::Lit(0); page_size]; page_count],
+            owners: vec![vec![None; page_size]; page_count],
             comments: vec![],
+            span: 0..0,
+            overflow: None,
+            overlapped: false,
+            diags: vec![],
+        }
+    }
+
+    fn finish_page(&mut self) {
+        if let (Some(page), Some((span, over))) = (self.current_page, self.overflow.take()) {
+            self.diags.push(
+                Diagnostic::error(
+                    format!(
+                        "page {} is {} byte{} over its {} byte limit",
+                        page,
+                        over,
+                        if over == 1 { "" } else { "s" },
+                        self.page_size
+                    ),
+                    span,
+                )
+                .with_label("this is the first byte past the end of the page"),
+            );
         }
     }
 
     pub fn set_page(&mut self, page: usize) {
-        self.current_page = page;
+        self.finish_page();
         self.current_page_ptr = 0;
+        self.overlapped = false;
+        if page < self.page_count {
+            self.current_page = Some(page);
+        } else {
+            self.current_page = None;
+            self.diags.push(
+                Diagnostic::error(format!("page {} does not exist", page), self.span.clone())
+                    .with_label(format!("pages are numbered 0 to {}", self.page_count - 1)),
+            );
+        }
+    }
+
+    // skipped bytes are left alone, so a later `.org` can still fill them in
+    pub fn set_offset(&mut self, offset: usize) {
+        self.current_page_ptr = offset;
+    }
+
+    // the node whose bytes are about to be written
+    pub fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 
     pub fn write(&mut self, value: u8) {
-        self.pages[self.current_page][self.current_page_ptr] = PageOutput::Lit(value);
+        let Some(page) = self.current_page else {
+            return;
+        };
+        let ptr = self.current_page_ptr;
         self.current_page_ptr += 1;
+        if ptr >= self.page_size {
+            self.overflow.get_or_insert((self.span.clone(), 0)).1 += 1;
+            return;
+        }
+        let prev = self.owners[page][ptr].replace(self.span.clone());
+        // one report per `>n` block is enough; the rest of the block usually overlaps too
+        if let Some(prev) = prev.filter(|_| !self.overlapped) {
+            self.overlapped = true;
+            self.diags.push(
+                Diagnostic::error(
+                    format!("overlapping write to page {} offset {}", page, ptr),
+                    self.span.clone(),
+                )
+                .with_label("this overwrites an earlier byte")
+                .with_secondary(prev, "first written here"),
+            );
+        }
+        self.pages[page][ptr] = PageOutput::Lit(value);
     }
 
     pub fn write_comment(&mut self, value: String) {
-        self.comments
-            .push((value, self.current_page * 32 + self.current_page_ptr));
+        if let Some(page) = self.current_page {
+            let ptr = self.current_page_ptr.min(self.page_size);
+            self.comments.push((value, page * self.page_size + ptr));
+        }
     }
 
-    pub fn get_pages(self) -> Vec<PageOutput> {
+    pub fn get_pages(mut self) -> (Vec<PageOutput>, Vec<Option<Span>>, Vec<Diagnostic>) {
+        self.finish_page();
+        let owners = self.owners.into_iter().flatten().collect();
         let mut ret: Vec<PageOutput> = self.pages.into_iter().flatten().collect();
         for (pos, comment) in self.comments.into_iter().enumerate() {
-            ret.insert(comment.1 + pos + 1, PageOutput::Comment(comment.0));
+            let at = (comment.1 + pos + 1).min(ret.len());
+            ret.insert(at, PageOutput::Comment(comment.0));
         }
-        ret
+        (ret, owners, self.diags)
     }
 }
 
@@ -47,44 +139,50 @@ pub enum PageOutput {
     Comment(String),
 }
 
-pub fn assemble(ast: Vec<CarbonASMProgram>) -> Vec<PageOutput> {
-    let mut pages = PageWriter::new();
-    for node in ast {
-        let mut word = 0;
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub words: Vec<PageOutput>,
+    pub page_size: usize,
+    pub page_count: usize,
+    // bytes written to each page
+    pub page_usage: Vec<usize>,
+    // span of the node that wrote each byte of the image, if any did
+    pub owners: Vec<Option<Span>>,
+    pub sections: Vec<Section>,
+    pub labels: LabelMap,
+    pub constants: HashMap<String, ConstantDef>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Image {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.words
+            .iter()
+            .filter_map(|w| match w {
+                PageOutput::Lit(n) => Some(*n),
+                PageOutput::Comment(_) => None,
+            })
+            .collect()
+    }
+}
+
+// the returned image has no sections, symbols or warnings; those are up to the caller
+pub fn assemble(
+    ast: Vec<Spanned<CarbonASMProgram>>,
+    target: &Target,
+    diags: &mut Vec<Diagnostic>,
+) -> Image {
+    let mut pages = PageWriter::new(target);
+    for Spanned { node, span } in ast {
+        pages.set_span(span);
+        let mut word;
         match node {
             CarbonASMProgram::Immediate(i) => {
                 word = i;
             }
             CarbonASMProgram::Instruction(i) => {
-                match i.opcode {
-                    CarbonInstrVariants::Nop => word = 0,
-                    CarbonInstrVariants::Hlt => word = 0b11111000,
-                    CarbonInstrVariants::Add => word |= 0b00001000,
-                    CarbonInstrVariants::Sub => word |= 0b00010000,
-                    CarbonInstrVariants::Bsb => word |= 0b00011000,
-                    CarbonInstrVariants::Or => word |= 0b00100000,
-                    CarbonInstrVariants::Nor => word |= 0b00101000,
-                    CarbonInstrVariants::And => word |= 0b00110000,
-                    CarbonInstrVariants::Nand => word |= 0b00111000,
-                    CarbonInstrVariants::Xor => word |= 0b01000000,
-                    CarbonInstrVariants::Lia => word |= 0b01001000,
-                    CarbonInstrVariants::Ldi => word |= 0b01010000,
-                    CarbonInstrVariants::Adr => word |= 0b01011000,
-                    CarbonInstrVariants::Rld => word |= 0b01100000,
-                    CarbonInstrVariants::Rst => word |= 0b01101000,
-                    CarbonInstrVariants::Mst => word |= 0b01110000,
-                    CarbonInstrVariants::Mld => word |= 0b01111000,
-                    CarbonInstrVariants::Ics => word |= 0b10000000,
-                    CarbonInstrVariants::Jid => word |= 0b10001000,
-                    CarbonInstrVariants::Brc => word |= 0b10010000,
-                    CarbonInstrVariants::Dec => word |= 0b10011000,
-                    CarbonInstrVariants::Cmp => word |= 0b10100000,
-                    CarbonInstrVariants::Bsr => word |= 0b10101000,
-                    CarbonInstrVariants::Bsl => word |= 0b10110000,
-                    CarbonInstrVariants::Pst => word |= 0b10111000,
-                    CarbonInstrVariants::Pld => word |= 0b11000000,
-                    CarbonInstrVariants::Inc => word |= 0b11001000,
-                }
+                // the parser only lets through instructions the target has
+                word = target.instr(i.opcode).unwrap().opcode;
                 if let Some(v) = i.operand {
                     for operand in v {
                         match operand {
@@ -103,18 +201,115 @@ pub fn assemble(ast: Vec<CarbonASMProgram>) -> Vec<PageOutput> {
                 pages.write_comment(c);
                 continue;
             }
-            CarbonASMProgram::Label(_) => unreachable!(),
+            CarbonASMProgram::Label(_) | CarbonASMProgram::Section(..) => unreachable!(),
             CarbonASMProgram::PageLabel(n) => {
                 pages.set_page(n);
                 continue;
             }
-            CarbonASMProgram::LabelDeref(_) => unreachable!(),
+            CarbonASMProgram::Org(page, offset) => {
+                if let Some(page) = page {
+                    pages.set_page(page);
+                }
+                pages.set_offset(offset);
+                continue;
+            }
+            CarbonASMProgram::Align(n) => {
+                pages.set_offset(pages.current_page_ptr.div_ceil(n) * n);
+                continue;
+            }
+            CarbonASMProgram::LabelDeref(_) | CarbonASMProgram::Expr(_) => unreachable!(),
         }
         pages.write(word);
     }
-    pages.get_pages()
+    let (words, owners, mut errors) = pages.get_pages();
+    diags.append(&mut errors);
+    let page_usage = owners
+        .chunks(target.page_size)
+        .map(|page| page.iter().filter(|o| o.is_some()).count())
+        .collect();
+    Image {
+        words,
+        page_size: target.page_size,
+        page_count: target.page_count,
+        page_usage,
+        owners,
+        sections: vec![],
+        labels: LabelMap::new(),
+        constants: HashMap::new(),
+        warnings: vec![],
+    }
 }
 
 fn write_cond(cond: CarbonConds) -> u8 {
     cond as u8
 }
+
+pub fn read_cond(word: u8) -> CarbonConds {
+    match word & 0b111 {
+        0 => CarbonConds::Even,
+        1 => CarbonConds::Jmp,
+        2 => CarbonConds::Eq,
+        3 => CarbonConds::Neq,
+        4 => CarbonConds::Lt,
+        5 => CarbonConds::Gt,
+        6 => CarbonConds::Gteq,
+        _ => CarbonConds::Lteq,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble_source;
+
+    fn opcode(mnemonic: &str) -> u8 {
+        assemble_source(mnemonic).unwrap().bytes()[0]
+    }
+
+    #[test]
+    fn org_and_align() {
+        let src = "LIA\n[entry]\nBRC JMP [table]\n.org 0:8\n.table\n.db 1, 2, 3\n.align 4\n.four\n.align 4\nHLT\n.org 2:16\n.entry\nNOP";
+        let image = assemble_source(src).unwrap();
+        let bytes = image.bytes();
+        assert_eq!(bytes[8..13], [1, 2, 3, 0, opcode("HLT")]);
+        assert_eq!(bytes[2 * 32 + 16], opcode("NOP"));
+        // labels are placed where their bytes end up, not where they'd have run on to
+        let at = |name: &str| (image.labels[name].page, image.labels[name].pc);
+        assert_eq!(at("table"), (0, 7));
+        assert_eq!(at("four"), (0, 11));
+        assert_eq!(at("entry"), (2, 15));
+        assert_eq!(bytes[1], 15);
+        assert_eq!(bytes[3], 7 << 3);
+        assert_eq!(image.page_usage[..3], [8, 0, 1]);
+    }
+
+    #[test]
+    fn org_overlap() {
+        let diags = assemble_source(".db 1, 2, 3\n.org 0:1\n5\n6").unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "overlapping write to page 0 offset 1");
+        assert_eq!(diags[0].span, 21..22);
+        assert_eq!(
+            diags[0].secondary,
+            [(7..8, "first written here".to_string())]
+        );
+
+        // going back to fill a gap is fine
+        let image = assemble_source(".org 4\n1\n.org 0\n2").unwrap();
+        assert_eq!(image.bytes()[..5], [2, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn bad_placement() {
+        for (src, message) in [
+            (".org 40:0", "page 40 does not exist"),
+            (".org 1:32", "offset 32 is outside the page"),
+            (".org -1", "offset -1 is outside the page"),
+            (".align 0", "can't align to 0"),
+            (".align 33", "can't align to 33"),
+            (".org 31\n1\n2", "page 0 is 1 byte over its 32 byte limit"),
+        ] {
+            let diags = assemble_source(src).unwrap_err();
+            assert_eq!(diags[0].message, message, "{}", src);
+        }
+    }
+}
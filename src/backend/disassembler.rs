@@ -0,0 +1,320 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::backend::assembler::read_cond;
+use crate::diagnostic::Diagnostic;
+use crate::instr::{CarbonConds, CarbonInstrVariants};
+use crate::isa::{Operand, Target};
+
+// true if `data` looks like the `// PAGE n` text dump rather than a raw binary
+pub fn is_page_dump(data: &[u8]) -> bool {
+    match std::str::from_utf8(data) {
+        Ok(text) => text
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty())
+            .is_some_and(|l| {
+                l.starts_with("//")
+                    || (l.len() >= 8 && l[..8].bytes().all(|b| b == b'0' || b == b'1'))
+            }),
+        Err(_) => false,
+    }
+}
+
+// reads the `// PAGE n` format written by the assembler back into a flat image
+pub fn parse_page_dump(src: &str, target: &Target, diags: &mut Vec<Diagnostic>) -> Vec<u8> {
+    let (page_size, page_count) = (target.page_size, target.page_count);
+    let mut ret = vec![0; target.rom_size()];
+    let mut page = 0;
+    let mut ptr = 0;
+    let mut line_start = 0;
+    for line in src.split_inclusive('\n') {
+        let start = line_start;
+        line_start += line.len();
+        let Some(word) = line.split_whitespace().next() else {
+            continue;
+        };
+        let span = start + (word.as_ptr() as usize - line.as_ptr() as usize);
+        let span = span..span + word.len();
+
+        if let Some(header) = line.trim().strip_prefix("// PAGE") {
+            match header.split_whitespace().next().map(str::parse::<usize>) {
+                Some(Ok(n)) if n < page_count => {
+                    page = n;
+                    ptr = 0;
+                }
+                _ => diags.push(
+                    Diagnostic::error("invalid page header", span.start..line_start)
+                        .with_label(format!("expected a page number below {}", page_count)),
+                ),
+            }
+        } else if word.starts_with("//") {
+            continue;
+        } else if word.len() != 8 || u8::from_str_radix(word, 2).is_err() {
+            diags.push(
+                Diagnostic::error(format!("invalid word `{}`", word), span)
+                    .with_label("expected 8 binary digits"),
+            );
+        } else if ptr >= page_size {
+            diags.push(
+                Diagnostic::error(
+                    format!("page {} has more than {} words", page, page_size),
+                    span,
+                )
+                .with_label("this word does not fit"),
+            );
+        } else {
+            ret[page * page_size + ptr] = u8::from_str_radix(word, 2).unwrap();
+            ptr += 1;
+        }
+    }
+    ret
+}
+
+enum Item {
+    Instr(CarbonInstrVariants, String),
+    Jump(CarbonInstrVariants, CarbonConds, u8),
+    Data(u8),
+}
+
+struct Decoded {
+    offset: usize,
+    len: usize,
+    item: Item,
+    // immediate byte following LIA/LDI
+    imm: Option<u8>,
+}
+
+fn decode_page(page: &[u8], target: &Target) -> Vec<Decoded> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    while pos < page.len() {
+        let word = page[pos];
+        let operand = word & 0b111;
+        let next = page.get(pos + 1).copied();
+        let mut decoded = Decoded {
+            offset: pos,
+            len: 1,
+            item: Item::Data(word),
+            imm: None,
+        };
+        let Some(def) = target.decode(word) else {
+            pos += decoded.len;
+            ret.push(decoded);
+            continue;
+        };
+        let reg = def.operands.contains(&Operand::Reg);
+        let imm = def.operands.contains(&Operand::Imm);
+        match def.operands {
+            // anything in the operand bits of an instruction without one can't be written back
+            _ if !reg && !def.operands.contains(&Operand::Cond) && operand != 0 => (),
+            _ if imm && next.is_none() => (),
+            // jump addresses only carry five bits, and ICS can only select pages that
+            // exist; anything else can't be written back as source
+            [Operand::Cond, Operand::Addr]
+                if next.is_some_and(|a| {
+                    a & 0b111 == 0
+                        && (def.instr != CarbonInstrVariants::Ics
+                            || ((a >> 3) as usize) < target.page_count)
+                }) =>
+            {
+                decoded.item = Item::Jump(def.instr, read_cond(word), next.unwrap() >> 3);
+                decoded.len = 2;
+            }
+            [Operand::Cond, Operand::Addr] => (),
+            _ => {
+                let text = match reg {
+                    true => format!("{} r{}", def.mnemonic, operand),
+                    false => def.mnemonic.to_string(),
+                };
+                decoded.item = Item::Instr(def.instr, text);
+                if imm {
+                    decoded.imm = next;
+                    decoded.len = 2;
+                }
+            }
+        }
+        pos += decoded.len;
+        ret.push(decoded);
+    }
+    ret
+}
+
+fn label_name(page: usize, offset: usize) -> String {
+    format!("l{}_{}", page, offset)
+}
+
+// branch destinations for every BRC, as (page, offset), going by the same rules
+// the assembler uses to decide whether a jump needs its page selecting: an
+// `ICS JMP` picks the page until the next jump or label, and anything else means
+// the current page. Where that can't say which page a branch goes to, as after a
+// conditional ICS, it's left as None so the address is written as a number
+fn branch_targets(
+    pages: &[Vec<Decoded>],
+    page_size: usize,
+    labels: &BTreeSet<(usize, usize)>,
+) -> Vec<Vec<Option<(usize, usize)>>> {
+    pages
+        .iter()
+        .enumerate()
+        .map(|(page, items)| {
+            // Some(page selected), or None if that isn't known
+            let mut selected = Some(page);
+            items
+                .iter()
+                .map(|d| {
+                    // the assembler forgets a selection at a label, since code jumping
+                    // there could have selected anything
+                    if labels.contains(&(page, d.offset)) && selected != Some(page) {
+                        selected = None;
+                    }
+                    match &d.item {
+                        Item::Jump(CarbonInstrVariants::Ics, cond, addr) => {
+                            selected = match cond {
+                                CarbonConds::Jmp => Some(*addr as usize),
+                                _ => None,
+                            };
+                            None
+                        }
+                        Item::Jump(_, _, addr) => {
+                            let target = selected.map(|p| (p, (*addr as usize + 1) % page_size));
+                            selected = Some(page);
+                            target
+                        }
+                        Item::Instr(CarbonInstrVariants::Jid, _) => {
+                            selected = Some(page);
+                            None
+                        }
+                        _ => None,
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn disassemble(image: &[u8], target: &Target) -> String {
+    let page_size = target.page_size;
+    let mut image = image.to_vec();
+    image.resize(target.rom_size(), 0);
+    let pages: Vec<Vec<Decoded>> = image
+        .chunks(page_size)
+        .map(|page| decode_page(page, target))
+        .collect();
+    // only targets that land on the start of a decoded instruction get a label.
+    // a label can hide which page a branch after it goes to, taking that branch's
+    // label away, so labels are dropped until every one left is still used
+    let found = |targets: &[Vec<Option<(usize, usize)>>]| -> BTreeSet<(usize, usize)> {
+        targets
+            .iter()
+            .flatten()
+            .flatten()
+            .filter(|(page, offset)| pages[*page].iter().any(|d| d.offset == *offset))
+            .copied()
+            .collect()
+    };
+    let mut labels = found(&branch_targets(&pages, page_size, &BTreeSet::new()));
+    let mut targets = branch_targets(&pages, page_size, &labels);
+    loop {
+        let kept: BTreeSet<_> = found(&targets).intersection(&labels).copied().collect();
+        if kept == labels {
+            break;
+        }
+        labels = kept;
+        targets = branch_targets(&pages, page_size, &labels);
+    }
+
+    let mut out = String::new();
+    for (page, items) in pages.iter().enumerate() {
+        let bytes = &image[page * page_size..(page + 1) * page_size];
+        let used = bytes.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        let labelled = labels
+            .range((page, 0)..(page + 1, 0))
+            .map(|(_, o)| o + 1)
+            .max()
+            .unwrap_or(0);
+        let end = used.max(labelled);
+        if end == 0 {
+            continue;
+        }
+
+        writeln!(out, ">{}", page).unwrap();
+        for (n, d) in items.iter().enumerate().take_while(|(_, d)| d.offset < end) {
+            if labels.contains(&(page, d.offset)) {
+                writeln!(out, ".{}", label_name(page, d.offset)).unwrap();
+            }
+            match &d.item {
+                Item::Instr(_, text) => writeln!(out, "{}", text).unwrap(),
+                Item::Data(b) => writeln!(out, "{}", b).unwrap(),
+                Item::Jump(i, cond, addr) => match targets[page][n] {
+                    Some(target) if labels.contains(&target) => {
+                        let label = label_name(target.0, target.1);
+                        writeln!(out, "{} {} [{}]", i, cond, label).unwrap()
+                    }
+                    _ => writeln!(out, "{} {} {}", i, cond, addr).unwrap(),
+                },
+            }
+            if let Some(imm) = d.imm {
+                writeln!(out, "{}", imm).unwrap();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // disassembling and assembling again has to give back the same image
+    fn round_trip(image: &[u8]) -> String {
+        let target = Target::default();
+        let src = disassemble(image, &target);
+        let mut bytes = match crate::assemble_source(&src) {
+            Ok(out) => out.bytes(),
+            Err(diags) => panic!("{:?}\n{}", diags, src),
+        };
+        let mut image = image.to_vec();
+        bytes.resize(target.rom_size(), 0);
+        image.resize(target.rom_size(), 0);
+        assert!(bytes == image, "{}", src);
+        src
+    }
+
+    #[test]
+    fn conditional_page_select() {
+        // ICS EQ 1 / BRC EQ 31, with something at the start of page 1
+        let mut image = vec![0x82, 0x08, 0x92, 0xf8];
+        image.resize(33, 0);
+        image[32] = 0xf8;
+        let src = round_trip(&image);
+        assert!(src.contains("ICS EQ 1\nBRC EQ 31\n"), "{}", src);
+    }
+
+    #[test]
+    fn label_after_page_select() {
+        // ICS JMP 1 / NOP / BRC JMP 4 / BRC JMP 1, and HLT at page 1 offset 5;
+        // the second branch puts a label on the NOP, so the first can't tell
+        // whether its page is still selected
+        let mut image = vec![0x81, 0x08, 0x00, 0x91, 0x20, 0x91, 0x08];
+        image.resize(38, 0);
+        image[37] = 0xf8;
+        let src = round_trip(&image);
+        assert!(
+            src.contains(".l0_2\nNOP\nBRC JMP 4\nBRC JMP [l0_2]\n"),
+            "{}",
+            src
+        );
+    }
+
+    #[test]
+    fn assembled_program() {
+        let src = "LIA 3\n.loop\nDEC\nBRC NEQ [loop]\nBRC JMP [far]\n\
+                   >1\n.far\nICS JMP 2\nLDI r1 3\nBRC JMP [end]\nJID r1\n\
+                   >2\nNOP\n.end\nHLT";
+        let image = crate::assemble_source(src).unwrap().bytes();
+        let src = round_trip(&image);
+        assert!(src.contains("BRC NEQ [l0_2]"), "{}", src);
+        assert!(src.contains("BRC JMP [l2_1]"), "{}", src);
+    }
+}
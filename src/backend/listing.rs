@@ -0,0 +1,138 @@
+use std::fmt::Write;
+
+use crate::backend::assembler::Image;
+use crate::diagnostic::{SourceMap, Span};
+
+// bytes shown on one row; longer runs carry on over the rows below
+const ROW_BYTES: usize = 4;
+
+struct Row {
+    offset: usize,
+    bytes: Vec<u8>,
+    // `file:line` and the text of the line, for the first row of each run
+    source: Option<(String, String)>,
+}
+
+// `file:line` and the text of the line a span was written on, after macro expansion
+fn source_line(sources: &SourceMap, span: &Span) -> (String, String) {
+    let (real, _) = sources.resolve(span);
+    let (file, local) = sources.file(real.start);
+    let (line, _, line_span) = file.locate(local);
+    let text = file.src[line_span].trim_end_matches('\r').trim();
+    (format!("{}:{}", file.name, line), text.to_string())
+}
+
+// what identifies a run of bytes: the source line and the macro calls it came through,
+// so one line expanded twice in a row still gets two runs
+fn run_key(sources: &SourceMap, span: &Span) -> (usize, Vec<Span>) {
+    let (real, trace) = sources.resolve(span);
+    let calls = trace.into_iter().map(|(_, span)| span).collect();
+    (sources.line_start(real.start), calls)
+}
+
+fn page_rows(image: &Image, sources: &SourceMap, page: usize, bytes: &[u8]) -> Vec<Row> {
+    let owners = &image.owners[page * image.page_size..(page + 1) * image.page_size];
+    let mut rows: Vec<Row> = Vec::new();
+    let mut last = None;
+    for (offset, owner) in owners.iter().enumerate() {
+        let Some(span) = owner else {
+            last = None;
+            continue;
+        };
+        let key = run_key(sources, span);
+        match rows.last_mut() {
+            Some(row) if last.as_ref() == Some(&key) && row.bytes.len() < ROW_BYTES => {
+                row.bytes.push(bytes[offset])
+            }
+            _ => rows.push(Row {
+                offset,
+                bytes: vec![bytes[offset]],
+                source: (last.as_ref() != Some(&key)).then(|| source_line(sources, span)),
+            }),
+        }
+        last = Some(key);
+    }
+    rows
+}
+
+/// A listing of everything written to `image`, one row per source line, with the
+/// page, offset, hex and binary encoding of its bytes and where labels landed.
+/// Rows are built from which node wrote each byte, so gaps left by `.org` and
+/// `.align` don't show up. `sources` has to be the map the image was built from.
+pub fn listing(image: &Image, sources: &SourceMap) -> String {
+    let bytes = image.bytes();
+    let mut labels: Vec<_> = image.labels.iter().collect();
+    labels.sort_by_key(|(name, def)| (def.page, def.pc, name.to_string()));
+
+    let rows: Vec<Vec<Row>> = (0..image.page_usage.len())
+        .map(|page| page_rows(image, sources, page, &bytes[page * image.page_size..]))
+        .collect();
+    let width = rows
+        .iter()
+        .flatten()
+        .filter_map(|r| r.source.as_ref())
+        .map(|(loc, _)| loc.len())
+        .chain(
+            labels
+                .iter()
+                .map(|(_, def)| source_line(sources, &def.span).0.len()),
+        )
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    let hex_width = ROW_BYTES * 3 - 1;
+    let bin_width = ROW_BYTES * 9 - 1;
+    writeln!(
+        out,
+        "page:off  {:hex_width$}  {:bin_width$}  {:width$}  source",
+        "hex", "binary", "line"
+    )
+    .unwrap();
+    for (page, rows) in rows.iter().enumerate() {
+        let mut labels = labels.iter().filter(|(_, def)| def.page == page).peekable();
+        if rows.is_empty() && labels.peek().is_none() {
+            continue;
+        }
+        writeln!(out).unwrap();
+        let mut rows = rows.iter().peekable();
+        loop {
+            // labels go just above the row holding the byte they point at
+            let label = labels
+                .next_if(|(_, def)| rows.peek().is_none_or(|row| def.pc < row.offset as isize));
+            if let Some((name, def)) = label {
+                let (loc, _) = source_line(sources, &def.span);
+                writeln!(
+                    out,
+                    "{:4}:{:02}  {:hex_width$}  {:bin_width$}  {:width$}  .{} = {}",
+                    page,
+                    def.pc + 1,
+                    "",
+                    "",
+                    loc,
+                    name,
+                    def.pc.rem_euclid(image.page_size as isize)
+                )
+                .unwrap();
+                continue;
+            }
+            let Some(row) = rows.next() else {
+                break;
+            };
+            let hex: Vec<_> = row.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let bin: Vec<_> = row.bytes.iter().map(|b| format!("{:08b}", b)).collect();
+            let (loc, text) = row.source.clone().unwrap_or_default();
+            let line = format!(
+                "{:4}:{:02}  {:hex_width$}  {:bin_width$}  {:width$}  {}",
+                page,
+                row.offset,
+                hex.join(" "),
+                bin.join(" "),
+                loc,
+                text
+            );
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+    }
+    out
+}
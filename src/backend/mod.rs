@@ -1 +1,6 @@
 pub mod assembler;
+pub mod disassembler;
+pub mod listing;
+pub mod output;
+pub mod schematic;
+pub mod symbols;
@@ -0,0 +1,167 @@
+use std::io::{self, Write};
+
+use crate::backend::assembler::{Image, PageOutput};
+
+/// Turns an assembled image into the bytes of an output file.
+pub trait ImageWriter {
+    /// Extension for the file, used when no output path is given.
+    fn extension(&self) -> &'static str;
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// The `// PAGE n` format: one `{:08b}` line per byte, with comments from the
+/// source kept after the byte they follow.
+pub struct TextWriter;
+
+impl ImageWriter for TextWriter {
+    fn extension(&self) -> &'static str {
+        "b"
+    }
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "// PAGE 0")?;
+        let page_size = image.page_size;
+        let mut ctr = 0;
+        for word in image.words.iter() {
+            if ctr % page_size == 0 && ctr / page_size != 0 {
+                write!(out, "\n// PAGE {}", ctr / page_size)?;
+            }
+
+            match word {
+                PageOutput::Lit(n) => {
+                    ctr += 1;
+                    write!(out, "\n{:08b}", n)?;
+                }
+                PageOutput::Comment(n) => write!(out, " {}", *n)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// The image as it sits in ROM, every page included.
+pub struct BinWriter;
+
+impl ImageWriter for BinWriter {
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&image.bytes())
+    }
+}
+
+/// Sixteen bytes a line in hex, each line starting with its address.
+pub struct HexWriter;
+
+impl ImageWriter for HexWriter {
+    fn extension(&self) -> &'static str {
+        "hex"
+    }
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()> {
+        for (n, line) in image.bytes().chunks(16).enumerate() {
+            let bytes: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{:04x}  {}", n * 16, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Intel HEX, in data records of sixteen bytes and an end of file record.
+pub struct IntelHexWriter;
+
+// one `:llaaaatt...cc` line; the checksum makes all of its bytes sum to zero
+fn ihex_record(out: &mut dyn Write, addr: u16, kind: u8, data: &[u8]) -> io::Result<()> {
+    let [hi, lo] = addr.to_be_bytes();
+    let mut record = vec![data.len() as u8, hi, lo, kind];
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+    let hex: String = record.iter().map(|b| format!("{:02X}", b)).collect();
+    writeln!(out, ":{}", hex)
+}
+
+impl ImageWriter for IntelHexWriter {
+    fn extension(&self) -> &'static str {
+        "ihex"
+    }
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()> {
+        for (n, data) in image.bytes().chunks(16).enumerate() {
+            ihex_record(out, (n * 16) as u16, 0x00, data)?;
+        }
+        ihex_record(out, 0, 0x01, &[])
+    }
+}
+
+/// A Logisim-evolution `v2.0 raw` memory file, for loading into a ROM component.
+/// With a page set, only that page is written, for a ROM per page.
+pub struct LogisimWriter {
+    pub page: Option<usize>,
+}
+
+impl ImageWriter for LogisimWriter {
+    fn extension(&self) -> &'static str {
+        "txt"
+    }
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()> {
+        let bytes = image.bytes();
+        let bytes = match self.page {
+            Some(page) => &bytes[page * image.page_size..(page + 1) * image.page_size],
+            None => &bytes[..],
+        };
+        writeln!(out, "v2.0 raw")?;
+        // unused space is mostly trailing zeros, which logisim fills in by itself
+        let used = bytes.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        for line in bytes[..used].chunks(16) {
+            let words: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{}", words.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(addr: u16, kind: u8, data: &[u8]) -> String {
+        let mut out = Vec::new();
+        ihex_record(&mut out, addr, kind, data).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // the example record from the Intel HEX spec
+    #[test]
+    fn ihex_checksum() {
+        let data = [
+            0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7e, 0xfe, 0x09, 0xd2,
+            0x19, 0x01,
+        ];
+        assert_eq!(
+            record(0x0100, 0x00, &data),
+            ":10010000214601360121470136007EFE09D2190140\n"
+        );
+    }
+
+    #[test]
+    fn ihex_eof() {
+        assert_eq!(record(0, 0x01, &[]), ":00000001FF\n");
+    }
+
+    #[test]
+    fn ihex_image() {
+        let image = crate::assemble_source("LIA 0x12\nHLT").unwrap();
+        let mut out = Vec::new();
+        IntelHexWriter.write(&image, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), image.bytes().len() / 16 + 1);
+        assert!(lines[0].starts_with(":10000000"));
+        assert_eq!(lines.last(), Some(&":00000001FF"));
+    }
+}
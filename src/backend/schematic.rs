@@ -0,0 +1,289 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use flate2::{write::GzEncoder, Compression};
+
+use crate::backend::assembler::{Image, PAGE_COUNT, PAGE_SIZE};
+use crate::backend::output::ImageWriter;
+
+// 1.20.1; WorldEdit upgrades older palettes but refuses schematics from the future
+const DATA_VERSION: i32 = 3465;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl fmt::Display for Axis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Axis::X => write!(f, "x"),
+            Axis::Y => write!(f, "y"),
+            Axis::Z => write!(f, "z"),
+        }
+    }
+}
+
+/// Which way consecutive bits, bytes or pages run, and how many blocks apart they are.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub axis: Axis,
+    pub spacing: usize,
+}
+
+/// A Sponge schematic (version 2) of the ROM, with one block per bit. Bit 0 of the
+/// first byte of page 0 is at the origin and everything between bits is air.
+#[derive(Debug, Clone)]
+pub struct SchematicWriter {
+    /// Block for a 0 bit, such as `minecraft:glass`.
+    pub zero: String,
+    /// Block for a 1 bit.
+    pub one: String,
+    pub bit: Placement,
+    pub byte: Placement,
+    pub page: Placement,
+}
+
+impl SchematicWriter {
+    /// Checks the layout makes sense: every level on its own axis, at least one
+    /// block apart, and small enough for the schematic's 16 bit dimensions.
+    pub fn validate(&self) -> Result<(), String> {
+        let levels = [
+            ("bits", self.bit, 8),
+            ("bytes", self.byte, PAGE_SIZE),
+            ("pages", self.page, PAGE_COUNT),
+        ];
+        for (n, (name, placement, count)) in levels.iter().enumerate() {
+            if let Some((other, _, _)) = levels[..n].iter().find(|l| l.1.axis == placement.axis) {
+                return Err(format!(
+                    "{} and {} are both laid out along {}",
+                    other, name, placement.axis
+                ));
+            }
+            if placement.spacing == 0 {
+                return Err(format!("{} need a spacing of at least 1", name));
+            }
+            let fits = (count - 1)
+                .checked_mul(placement.spacing)
+                .is_some_and(|len| len < u16::MAX as usize);
+            if !fits {
+                return Err(format!("{} are spaced too far apart to fit", name));
+            }
+        }
+        Ok(())
+    }
+
+    // size along each of x, y and z
+    fn size(&self, image: &Image) -> [usize; 3] {
+        let mut ret = [1; 3];
+        for (placement, count) in [
+            (self.bit, 8),
+            (self.byte, image.page_size),
+            (self.page, image.page_count),
+        ] {
+            ret[placement.axis as usize] = (count - 1) * placement.spacing + 1;
+        }
+        ret
+    }
+}
+
+// just enough NBT for a schematic: tags are written as they're reached, and
+// compounds are closed by hand with `end`
+struct Nbt<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Nbt<W> {
+    fn tag(&mut self, id: u8, name: &str) -> io::Result<()> {
+        self.out.write_all(&[id])?;
+        self.string(name)
+    }
+
+    fn string(&mut self, s: &str) -> io::Result<()> {
+        self.out.write_all(&(s.len() as u16).to_be_bytes())?;
+        self.out.write_all(s.as_bytes())
+    }
+
+    fn compound(&mut self, name: &str) -> io::Result<()> {
+        self.tag(10, name)
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.out.write_all(&[0])
+    }
+
+    fn short(&mut self, name: &str, v: u16) -> io::Result<()> {
+        self.tag(2, name)?;
+        self.out.write_all(&v.to_be_bytes())
+    }
+
+    fn int(&mut self, name: &str, v: i32) -> io::Result<()> {
+        self.tag(3, name)?;
+        self.out.write_all(&v.to_be_bytes())
+    }
+
+    fn byte_array(&mut self, name: &str, v: &[u8]) -> io::Result<()> {
+        self.tag(7, name)?;
+        self.out.write_all(&(v.len() as i32).to_be_bytes())?;
+        self.out.write_all(v)
+    }
+
+    fn int_array(&mut self, name: &str, v: &[i32]) -> io::Result<()> {
+        self.tag(11, name)?;
+        self.out.write_all(&(v.len() as i32).to_be_bytes())?;
+        v.iter()
+            .try_for_each(|i| self.out.write_all(&i.to_be_bytes()))
+    }
+}
+
+impl ImageWriter for SchematicWriter {
+    fn extension(&self) -> &'static str {
+        "schem"
+    }
+
+    fn write(&self, image: &Image, out: &mut dyn Write) -> io::Result<()> {
+        let mut palette = vec!["minecraft:air"];
+        for block in [&self.zero, &self.one] {
+            if !palette.contains(&block.as_str()) {
+                palette.push(block);
+            }
+        }
+        let index = |block: &str| palette.iter().position(|b| *b == block).unwrap() as u8;
+
+        let [width, height, length] = self.size(image);
+        let mut blocks = vec![0; width * height * length];
+        for (addr, byte) in image.bytes().into_iter().enumerate() {
+            for bit in 0..8 {
+                let mut pos = [0; 3];
+                pos[self.bit.axis as usize] = bit * self.bit.spacing;
+                pos[self.byte.axis as usize] = addr % image.page_size * self.byte.spacing;
+                pos[self.page.axis as usize] = addr / image.page_size * self.page.spacing;
+                let block = if byte >> bit & 1 == 1 {
+                    &self.one
+                } else {
+                    &self.zero
+                };
+                blocks[pos[0] + pos[2] * width + pos[1] * width * length] = index(block);
+            }
+        }
+
+        // block data is varints, but a palette this small always fits in one byte
+        let mut nbt = Nbt {
+            out: GzEncoder::new(out, Compression::default()),
+        };
+        nbt.compound("Schematic")?;
+        nbt.int("Version", 2)?;
+        nbt.int("DataVersion", DATA_VERSION)?;
+        nbt.short("Width", width as u16)?;
+        nbt.short("Height", height as u16)?;
+        nbt.short("Length", length as u16)?;
+        nbt.int_array("Offset", &[0, 0, 0])?;
+        nbt.int("PaletteMax", palette.len() as i32)?;
+        nbt.compound("Palette")?;
+        for (n, block) in palette.iter().enumerate() {
+            nbt.int(block, n as i32)?;
+        }
+        nbt.end()?;
+        nbt.byte_array("BlockData", &blocks)?;
+        nbt.end()?;
+        nbt.out.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn writer(spacing: usize) -> SchematicWriter {
+        SchematicWriter {
+            zero: "minecraft:glass".to_string(),
+            one: "minecraft:redstone_block".to_string(),
+            bit: Placement {
+                axis: Axis::X,
+                spacing,
+            },
+            byte: Placement {
+                axis: Axis::Z,
+                spacing: 1,
+            },
+            page: Placement {
+                axis: Axis::Y,
+                spacing: 2,
+            },
+        }
+    }
+
+    // a named tag as it's written, without its payload
+    fn tag(id: u8, name: &str) -> Vec<u8> {
+        let mut ret = vec![id];
+        ret.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        ret.extend_from_slice(name.as_bytes());
+        ret
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .unwrap_or_else(|| panic!("no {:?} in the schematic", needle))
+    }
+
+    #[test]
+    fn schematic() {
+        let image = crate::assemble_source("LIA 0b1010_0101\nHLT").unwrap();
+        let schem = writer(2);
+        schem.validate().unwrap();
+        let mut out = Vec::new();
+        schem.write(&image, &mut out).unwrap();
+        let mut nbt = Vec::new();
+        GzDecoder::new(&out[..]).read_to_end(&mut nbt).unwrap();
+
+        let mut header = tag(10, "Schematic");
+        header.extend(tag(3, "Version"));
+        header.extend(2i32.to_be_bytes());
+        assert!(nbt.starts_with(&header));
+
+        let mut palette = tag(10, "Palette");
+        for (n, block) in [
+            "minecraft:air",
+            "minecraft:glass",
+            "minecraft:redstone_block",
+        ]
+        .iter()
+        .enumerate()
+        {
+            palette.extend(tag(3, block));
+            palette.extend((n as i32).to_be_bytes());
+        }
+        palette.push(0);
+        find(&nbt, &palette);
+
+        let [width, height, length] = schem.size(&image);
+        let start = find(&nbt, &tag(7, "BlockData")) + tag(7, "BlockData").len();
+        let len = i32::from_be_bytes(nbt[start..start + 4].try_into().unwrap());
+        assert_eq!(len as usize, width * height * length);
+        let blocks = &nbt[start + 4..start + 4 + len as usize];
+        // the first byte runs along x from the origin, with air between its bits
+        let byte = image.bytes()[0];
+        for bit in 0..8 {
+            assert_eq!(blocks[bit * 2] as usize, 1 + (byte >> bit & 1) as usize);
+            if bit < 7 {
+                assert_eq!(blocks[bit * 2 + 1], 0);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_spacing() {
+        assert!(writer(usize::MAX).validate().is_err());
+        assert!(writer(u16::MAX as usize / 7).validate().is_ok());
+        assert!(writer(u16::MAX as usize / 7 + 1).validate().is_err());
+    }
+}
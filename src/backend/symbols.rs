@@ -0,0 +1,109 @@
+use std::fmt::Write;
+
+use serde_json::json;
+
+use crate::backend::assembler::Image;
+use crate::diagnostic::{SourceMap, Span};
+use crate::frontend::parser::{ConstantDef, LabelDef};
+
+// file name and line a symbol was defined on, looking through macro expansions
+fn location<'a>(sources: &'a SourceMap, span: &Span) -> (&'a str, usize) {
+    let (real, _) = sources.resolve(span);
+    let (file, local) = sources.file(real.start);
+    (&file.name, file.locate(local).0)
+}
+
+// labels in address order
+fn labels(image: &Image) -> Vec<(&String, &LabelDef)> {
+    let mut ret: Vec<_> = image.labels.iter().collect();
+    ret.sort_by_key(|(name, def)| (def.page, def.pc, name.to_string()));
+    ret
+}
+
+fn constants(image: &Image) -> Vec<(&String, &ConstantDef)> {
+    let mut ret: Vec<_> = image.constants.iter().collect();
+    ret.sort_by_key(|(name, _)| name.to_string());
+    ret
+}
+
+/// The symbol table as JSON: every label with the page and offset of the byte it
+/// points at and the address jumps to it encode, every constant, and how full each
+/// section and page is. `sources` has to be the map the image was built from.
+pub fn symbols_json(image: &Image, sources: &SourceMap) -> String {
+    let labels: Vec<_> = labels(image)
+        .into_iter()
+        .map(|(name, def)| {
+            let (file, line) = location(sources, &def.span);
+            json!({
+                "name": name,
+                "page": def.page,
+                "offset": def.pc + 1,
+                "address": def.pc.rem_euclid(image.page_size as isize),
+                "file": file,
+                "line": line,
+            })
+        })
+        .collect();
+    let constants: Vec<_> = constants(image)
+        .into_iter()
+        .map(|(name, def)| {
+            let (file, line) = location(sources, &def.span);
+            json!({ "name": name, "value": def.value, "file": file, "line": line })
+        })
+        .collect();
+    let sections: Vec<_> = image
+        .sections
+        .iter()
+        .map(|s| {
+            let used: usize = image.page_usage[s.page..s.page + s.pages].iter().sum();
+            json!({
+                "name": s.name,
+                "page": s.page,
+                "pages": s.pages,
+                "pinned": s.pinned,
+                "used": used,
+                "size": s.pages * image.page_size,
+            })
+        })
+        .collect();
+    let pages: Vec<_> = image
+        .page_usage
+        .iter()
+        .enumerate()
+        .map(|(page, used)| json!({ "page": page, "used": used, "size": image.page_size }))
+        .collect();
+    let symbols = json!({
+        "labels": labels,
+        "constants": constants,
+        "sections": sections,
+        "pages": pages,
+    });
+    serde_json::to_string_pretty(&symbols).unwrap() + "\n"
+}
+
+/// The symbol table as plain text, one symbol per line: `name page offset` for
+/// labels and `name value` for constants, followed by usage of each section and
+/// of every page that has anything on it.
+pub fn symbols_text(image: &Image) -> String {
+    let mut out = String::from("# labels: name page offset\n");
+    for (name, def) in labels(image) {
+        writeln!(out, "{} {} {}", name, def.page, def.pc + 1).unwrap();
+    }
+    out.push_str("\n# constants: name value\n");
+    for (name, def) in constants(image) {
+        writeln!(out, "{} {}", name, def.value).unwrap();
+    }
+    out.push_str("\n# sections: name page pages used size\n");
+    for s in image.sections.iter() {
+        let used: usize = image.page_usage[s.page..s.page + s.pages].iter().sum();
+        let size = s.pages * image.page_size;
+        writeln!(out, "{} {} {} {} {}", s.name, s.page, s.pages, used, size).unwrap();
+    }
+    out.push_str("\n# pages: page used size\n");
+    for (page, used) in image.page_usage.iter().enumerate() {
+        if *used > 0 {
+            writeln!(out, "{} {} {}", page, used, image.page_size).unwrap();
+        }
+    }
+    out
+}
@@ -0,0 +1,367 @@
+use std::fmt::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+pub type Span = Range<usize>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Span,
+    pub label: Option<String>,
+    pub secondary: Vec<(Span, String)>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            level: Level::Error,
+            message: message.into(),
+            span,
+            label: None,
+            secondary: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            level: Level::Warning,
+            ..Self::error(message, span)
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level == Level::Error
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, label: impl Into<String>) -> Self {
+        self.secondary.push((span, label.into()));
+        self
+    }
+}
+
+pub fn error_count(diags: &[Diagnostic]) -> usize {
+    diags.iter().filter(|d| d.is_error()).count()
+}
+
+// trailing rustc-style summary line, e.g. "aborting due to 2 errors; 1 warning emitted"
+pub fn summary(diags: &[Diagnostic]) -> Option<String> {
+    let errors = error_count(diags);
+    let warnings = diags.len() - errors;
+    let plural = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+    match (errors, warnings) {
+        (0, 0) => None,
+        (0, w) => Some(format!("warning: {} emitted", plural(w, "warning"))),
+        (e, 0) => Some(format!("error: aborting due to {}", plural(e, "error"))),
+        (e, w) => Some(format!(
+            "error: aborting due to {}; {} emitted",
+            plural(e, "error"),
+            plural(w, "warning")
+        )),
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub src: String,
+}
+
+impl SourceFile {
+    pub fn new(name: impl Into<String>, src: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            src: src.into(),
+        }
+    }
+
+    // 1-based line and column of a byte offset, plus the byte range of that line
+    pub fn locate(&self, offset: usize) -> (usize, usize, Span) {
+        let offset = offset.min(self.src.len());
+        let line_start = self.src[..offset].rfind('\n').map_or(0, |p| p + 1);
+        let line_end = self.src[offset..]
+            .find('\n')
+            .map_or(self.src.len(), |p| offset + p);
+        let line = self.src[..line_start].matches('\n').count() + 1;
+        let col = self.src[line_start..offset].chars().count() + 1;
+        (line, col, line_start..line_end)
+    }
+
+    // source line with `marker` underlining the part of `span` that sits on its first line
+    fn snippet(
+        &self,
+        out: &mut String,
+        span: &Span,
+        marker: &str,
+        label: Option<&String>,
+        gutter: usize,
+    ) {
+        let (line, _, line_span) = self.locate(span.start);
+        let text = self.src[line_span.clone()].trim_end_matches('\r');
+        let pad = " ".repeat(gutter);
+
+        let start = span.start.min(line_span.end);
+        let end = span.end.clamp(start, line_span.end);
+        let width = self.src[start..end].chars().count().max(1);
+        let indent: String = self.src[line_span.start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(out, "{pad} |").unwrap();
+        writeln!(out, "{line:>gutter$} | {text}").unwrap();
+        write!(out, "{pad} | {indent}{}", marker.repeat(width)).unwrap();
+        if let Some(label) = label {
+            write!(out, " {}", label).unwrap();
+        }
+    }
+}
+
+// a macro expansion; the body's tokens are given fresh spans starting at `base` so
+// anything reported inside it can be traced back through the call site
+struct Expansion {
+    name: String,
+    call: Span,
+    body: Span,
+    base: usize,
+}
+
+// runs of the same frame, as from a recursive macro, are shown once with a count
+fn collapse(trace: &[(String, Span)]) -> Vec<(String, &Span)> {
+    let mut ret: Vec<(&String, &Span, usize)> = Vec::new();
+    for (label, span) in trace {
+        match ret.last_mut() {
+            Some((l, s, count)) if *l == label && *s == span => *count += 1,
+            _ => ret.push((label, span, 1)),
+        }
+    }
+    ret.into_iter()
+        .map(|(label, span, n)| match n {
+            1 => (label.clone(), span),
+            n => (format!("{} ({} times)", label, n), span),
+        })
+        .collect()
+}
+
+struct FileEntry {
+    base: usize,
+    file: SourceFile,
+    // the `.include` that pulled this file in
+    included_from: Option<Span>,
+}
+
+/// Every file that went into a build, laid out one after another so a plain byte
+/// offset is enough to tell them apart, plus the virtual ranges handed out to macro
+/// expansions.
+pub struct SourceMap {
+    files: Vec<FileEntry>,
+    expansions: Vec<Expansion>,
+    end: usize,
+    /// Directories searched by `.include` after the including file's own.
+    pub include_paths: Vec<PathBuf>,
+}
+
+impl Default for SourceMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self {
+            files: vec![],
+            expansions: vec![],
+            end: 0,
+            include_paths: vec![],
+        }
+    }
+
+    // returns the offset the file's spans start at
+    pub fn add_file(&mut self, file: SourceFile) -> usize {
+        self.push_file(file, None)
+    }
+
+    // like `add_file`, for a file pulled in by the `.include` at `from`
+    pub fn include_file(&mut self, file: SourceFile, from: Span) -> usize {
+        self.push_file(file, Some(from))
+    }
+
+    fn push_file(&mut self, file: SourceFile, included_from: Option<Span>) -> usize {
+        let base = self.end;
+        // leave room for an end of input span after the last byte
+        self.end += file.src.len() + 1;
+        self.files.push(FileEntry {
+            base,
+            file,
+            included_from,
+        });
+        base
+    }
+
+    // every file with the offset its spans start at, in the order they were added
+    pub fn files(&self) -> impl Iterator<Item = (usize, &SourceFile)> {
+        self.files.iter().map(|f| (f.base, &f.file))
+    }
+
+    // the `.include`s that pulled in the file a real span is in, innermost first,
+    // each with the name of the file it included
+    pub fn include_chain(&self, span: &Span) -> Vec<(&str, Span)> {
+        let mut ret = Vec::new();
+        let mut entry = self.entry(span.start);
+        while let Some(from) = &entry.included_from {
+            ret.push((entry.file.name.as_str(), from.clone()));
+            entry = self.entry(from.start);
+        }
+        ret
+    }
+
+    // reserves spans for one expansion of a macro whose body covers `body`;
+    // returns the expansion's id and the offset its spans start at
+    pub fn expand(&mut self, name: &str, call: Span, body: Span) -> (usize, usize) {
+        let base = self.end;
+        self.end += body.len() + 1;
+        self.expansions.push(Expansion {
+            name: name.to_string(),
+            call,
+            body,
+            base,
+        });
+        (self.expansions.len() - 1, base)
+    }
+
+    fn expansion(&self, offset: usize) -> Option<&Expansion> {
+        self.expansions
+            .iter()
+            .find(|e| (e.base..=e.base + e.body.len()).contains(&offset))
+    }
+
+    // how many macro calls deep a span is
+    pub fn depth(&self, span: &Span) -> usize {
+        self.resolve(span).1.len()
+    }
+
+    /// The span in a real file that `span` stands for, and how it got there: the
+    /// macro calls it was expanded from and the `.include`s of the file it ended up
+    /// in, innermost first, each with a label to show next to it.
+    pub fn resolve(&self, span: &Span) -> (Span, Vec<(String, Span)>) {
+        let Some(e) = self.expansion(span.start) else {
+            let trace = self
+                .include_chain(span)
+                .into_iter()
+                .map(|(name, from)| (format!("`{}` included here", name), from))
+                .collect();
+            return (span.clone(), trace);
+        };
+        let start = e.body.start + (span.start - e.base);
+        let end = e.body.start + (span.end.max(span.start) - e.base);
+        // the body itself may have been written inside another expansion
+        let (real, _) = self.resolve(&(start..end.min(e.body.end)));
+        let (call, outer) = self.resolve(&e.call);
+        let mut trace = vec![(format!("in this expansion of `{}`", e.name), call)];
+        trace.extend(outer);
+        (real, trace)
+    }
+
+    fn entry(&self, offset: usize) -> &FileEntry {
+        self.files
+            .iter()
+            .rev()
+            .find(|f| f.base <= offset)
+            .unwrap_or(&self.files[0])
+    }
+
+    // the file a real offset is in, and the offset within it
+    pub fn file(&self, offset: usize) -> (&SourceFile, usize) {
+        let entry = self.entry(offset);
+        (&entry.file, offset.saturating_sub(entry.base))
+    }
+
+    // start of the line a real offset is on, as an offset into the map
+    pub fn line_start(&self, offset: usize) -> usize {
+        let (file, local) = self.file(offset);
+        let (_, _, line) = file.locate(local);
+        offset - (local - line.start)
+    }
+
+    fn location(&self, span: &Span) -> (&SourceFile, Span) {
+        let (file, start) = self.file(span.start);
+        (file, start..start + span.len())
+    }
+
+    pub fn render(&self, diag: &Diagnostic) -> String {
+        let (span, trace) = self.resolve(&diag.span);
+        let (file, span) = self.location(&span);
+        let secondary: Vec<_> = diag
+            .secondary
+            .iter()
+            .map(|(s, label)| (self.location(&self.resolve(s).0), label.clone()))
+            .chain(
+                collapse(&trace)
+                    .into_iter()
+                    .map(|(label, span)| (self.location(span), label)),
+            )
+            .collect();
+
+        let (line, col, _) = file.locate(span.start);
+        let gutter = std::iter::once((file, &span))
+            .chain(secondary.iter().map(|((f, s), _)| (*f, s)))
+            .map(|(f, s)| f.locate(s.start).0.to_string().len())
+            .max()
+            .unwrap();
+        let pad = " ".repeat(gutter);
+
+        let mut out = String::new();
+        writeln!(out, "{}: {}", diag.level, diag.message).unwrap();
+        writeln!(out, "{pad}--> {}:{}:{}", file.name, line, col).unwrap();
+        file.snippet(&mut out, &span, "^", diag.label.as_ref(), gutter);
+        for ((file, span), label) in secondary.iter() {
+            let (line, col, _) = file.locate(span.start);
+            write!(out, "\n{pad}::: {}:{}:{}\n", file.name, line, col).unwrap();
+            file.snippet(&mut out, span, "-", Some(label), gutter);
+        }
+        for note in diag.notes.iter() {
+            write!(out, "\n{pad} = note: {}", note).unwrap();
+        }
+        out
+    }
+}
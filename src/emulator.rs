@@ -0,0 +1,401 @@
+use std::fmt;
+
+use crate::backend::assembler::read_cond;
+use crate::instr::{CarbonConds, CarbonInstrVariants};
+use crate::isa::Target;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Flags {
+    pub zero: bool,
+    // set on unsigned overflow for additions, cleared on borrow for subtractions
+    pub carry: bool,
+    pub even: bool,
+}
+
+impl Flags {
+    pub fn test(&self, cond: &CarbonConds) -> bool {
+        match cond {
+            CarbonConds::Even => self.even,
+            CarbonConds::Jmp => true,
+            CarbonConds::Eq => self.zero,
+            CarbonConds::Neq => !self.zero,
+            CarbonConds::Lt => !self.carry,
+            CarbonConds::Gt => self.carry && !self.zero,
+            CarbonConds::Gteq => self.carry,
+            CarbonConds::Lteq => !self.carry || self.zero,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmulatorError {
+    InvalidOpcode {
+        page: usize,
+        pc: usize,
+        word: u8,
+    },
+    // an ICS selecting a page past the end of the ROM
+    InvalidPage {
+        page: usize,
+        pc: usize,
+        selected: usize,
+    },
+    CycleLimit(usize),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidOpcode { page, pc, word } => write!(
+                f,
+                "invalid opcode {:08b} at page {} offset {}",
+                word, page, pc
+            ),
+            EmulatorError::InvalidPage { page, pc, selected } => write!(
+                f,
+                "ICS at page {} offset {} selects page {}, which doesn't exist",
+                page, pc, selected
+            ),
+            EmulatorError::CycleLimit(n) => {
+                write!(f, "program did not halt within {} cycles", n)
+            }
+        }
+    }
+}
+
+pub struct Emulator {
+    pub target: Target,
+    pub rom: Vec<u8>,
+    pub acc: u8,
+    pub regs: [u8; 8],
+    pub mem: [u8; 256],
+    pub ports: [u8; 8],
+    pub flags: Flags,
+    // memory address selected by ADR, used by MST/MLD
+    pub addr: u8,
+    pub page: usize,
+    // page selected by ICS; taken on the next BRC/JID that jumps
+    pub next_page: usize,
+    pub pc: usize,
+    pub halted: bool,
+    pub cycles: usize,
+}
+
+impl Emulator {
+    pub fn new(mut rom: Vec<u8>, target: &Target) -> Emulator {
+        rom.resize(target.rom_size(), 0);
+        Emulator {
+            target: target.clone(),
+            rom,
+            acc: 0,
+            regs: [0; 8],
+            mem: [0; 256],
+            ports: [0; 8],
+            flags: Flags::default(),
+            addr: 0,
+            page: 0,
+            next_page: 0,
+            pc: 0,
+            halted: false,
+            cycles: 0,
+        }
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let word = self.rom[self.page * self.target.page_size + self.pc];
+        self.pc = (self.pc + 1) % self.target.page_size;
+        word
+    }
+
+    // the pc still increments after a taken branch, so jump addresses name the
+    // byte before the destination; this is also what transform_labels emits
+    fn jump(&mut self, offset: usize) {
+        self.page = self.next_page;
+        self.pc = (offset + 1) % self.target.page_size;
+    }
+
+    fn set_flags(&mut self, result: u8, carry: bool) {
+        self.flags = Flags {
+            zero: result == 0,
+            carry,
+            even: result & 1 == 0,
+        };
+    }
+
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        if self.halted {
+            return Ok(());
+        }
+        let (page, pc) = (self.page, self.pc);
+        let word = self.fetch();
+        let operand = (word & 0b111) as usize;
+        let instr = self
+            .target
+            .decode(word)
+            .map(|d| d.instr)
+            .ok_or(EmulatorError::InvalidOpcode { page, pc, word })?;
+        let a = self.acc;
+        let r = self.regs[operand];
+        self.cycles += 1;
+
+        match instr {
+            CarbonInstrVariants::Nop => (),
+            CarbonInstrVariants::Hlt => self.halted = true,
+            CarbonInstrVariants::Add => {
+                let (res, carry) = a.overflowing_add(r);
+                self.acc = res;
+                self.set_flags(res, carry);
+            }
+            CarbonInstrVariants::Nor => {
+                let res = a as u16 + r as u16 + self.flags.carry as u16;
+                self.acc = res as u8;
+                self.set_flags(res as u8, res > 0xff);
+            }
+            CarbonInstrVariants::Sub => {
+                self.acc = a.wrapping_sub(r);
+                self.set_flags(self.acc, a >= r);
+            }
+            CarbonInstrVariants::Bsb => {
+                self.acc = r.wrapping_sub(a);
+                self.set_flags(self.acc, r >= a);
+            }
+            CarbonInstrVariants::Cmp => self.set_flags(a.wrapping_sub(r), a >= r),
+            CarbonInstrVariants::Or => {
+                self.acc = a | r;
+                self.set_flags(self.acc, false);
+            }
+            CarbonInstrVariants::And => {
+                self.acc = a & r;
+                self.set_flags(self.acc, false);
+            }
+            CarbonInstrVariants::Nand => {
+                self.acc = !(a & r);
+                self.set_flags(self.acc, false);
+            }
+            CarbonInstrVariants::Xor => {
+                self.acc = a ^ r;
+                self.set_flags(self.acc, false);
+            }
+            CarbonInstrVariants::Bsr => {
+                self.acc = a >> (r & 0b111);
+                self.set_flags(self.acc, false);
+            }
+            CarbonInstrVariants::Bsl => {
+                self.acc = a << (r & 0b111);
+                self.set_flags(self.acc, false);
+            }
+            CarbonInstrVariants::Inc => {
+                let (res, carry) = a.overflowing_add(1);
+                self.acc = res;
+                self.set_flags(res, carry);
+            }
+            CarbonInstrVariants::Dec => {
+                self.acc = a.wrapping_sub(1);
+                self.set_flags(self.acc, a >= 1);
+            }
+            CarbonInstrVariants::Lia => self.acc = self.fetch(),
+            CarbonInstrVariants::Ldi => self.regs[operand] = self.fetch(),
+            CarbonInstrVariants::Rld => self.acc = r,
+            CarbonInstrVariants::Rst => self.regs[operand] = a,
+            CarbonInstrVariants::Adr => self.addr = r,
+            CarbonInstrVariants::Mst => self.mem[self.addr as usize] = r,
+            CarbonInstrVariants::Mld => self.regs[operand] = self.mem[self.addr as usize],
+            CarbonInstrVariants::Pst => self.ports[operand] = a,
+            CarbonInstrVariants::Pld => self.acc = self.ports[operand],
+            CarbonInstrVariants::Ics => {
+                let selected = (self.fetch() >> 3) as usize;
+                if self.flags.test(&read_cond(word)) {
+                    if selected >= self.target.page_count {
+                        return Err(EmulatorError::InvalidPage { page, pc, selected });
+                    }
+                    self.next_page = selected;
+                }
+            }
+            CarbonInstrVariants::Brc => {
+                let target = (self.fetch() >> 3) as usize;
+                if self.flags.test(&read_cond(word)) {
+                    self.jump(target);
+                }
+            }
+            CarbonInstrVariants::Jid => self.jump(r as usize),
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self, max_cycles: usize) -> Result<(), EmulatorError> {
+        while !self.halted {
+            if self.cycles >= max_cycles {
+                return Err(EmulatorError::CycleLimit(max_cycles));
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Emulator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} after {} cycles at page {} offset {}",
+            if self.halted { "halted" } else { "stopped" },
+            self.cycles,
+            self.page,
+            self.pc
+        )?;
+        writeln!(f, "acc: {:3} ({:08b})", self.acc, self.acc)?;
+        for (n, r) in self.regs.iter().enumerate() {
+            writeln!(f, "r{}:  {:3} ({:08b})", n, r, r)?;
+        }
+        writeln!(
+            f,
+            "flags: zero={} carry={} even={}",
+            self.flags.zero as u8, self.flags.carry as u8, self.flags.even as u8
+        )?;
+        writeln!(f, "addr: {}  next page: {}", self.addr, self.next_page)?;
+        write!(f, "ports:")?;
+        for p in self.ports.iter() {
+            write!(f, " {}", p)?;
+        }
+        // memory is mostly empty, so only show the bytes that were touched
+        for (addr, val) in self.mem.iter().enumerate().filter(|(_, v)| **v != 0) {
+            write!(f, "\nmem[{}] = {}", addr, val)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble_source;
+
+    fn run(src: &str) -> Emulator {
+        let image = assemble_source(src).unwrap();
+        let mut emu = Emulator::new(image.bytes(), &Target::default());
+        emu.run(1000).unwrap();
+        emu
+    }
+
+    #[test]
+    fn conditions() {
+        let flags = |zero, carry, even| Flags { zero, carry, even };
+        let cases = [
+            // (a, b) compared as by `CMP`: zero if equal, carry if there was no borrow
+            (
+                flags(true, true, true),
+                [true, true, true, false, false, false, true, true],
+            ),
+            (
+                flags(false, true, false),
+                [false, true, false, true, false, true, true, false],
+            ),
+            (
+                flags(false, false, true),
+                [true, true, false, true, true, false, false, true],
+            ),
+        ];
+        let conds = [
+            CarbonConds::Even,
+            CarbonConds::Jmp,
+            CarbonConds::Eq,
+            CarbonConds::Neq,
+            CarbonConds::Lt,
+            CarbonConds::Gt,
+            CarbonConds::Gteq,
+            CarbonConds::Lteq,
+        ];
+        for (flags, expected) in cases {
+            for (cond, expected) in conds.iter().zip(expected) {
+                assert_eq!(flags.test(cond), expected, "{} with {:?}", cond, flags);
+            }
+        }
+    }
+
+    #[test]
+    fn add_carries_out() {
+        let emu = run("LDI r1 200\nLIA 100\nADD r1\nHLT");
+        assert_eq!(emu.acc, 44);
+        assert_eq!(
+            emu.flags,
+            Flags {
+                zero: false,
+                carry: true,
+                even: true
+            }
+        );
+    }
+
+    #[test]
+    fn sub_clears_carry_on_borrow() {
+        let emu = run("LDI r1 5\nLIA 5\nSUB r1\nHLT");
+        assert_eq!(
+            emu.flags,
+            Flags {
+                zero: true,
+                carry: true,
+                even: true
+            }
+        );
+        let emu = run("LDI r1 5\nLIA 3\nSUB r1\nHLT");
+        assert_eq!(emu.acc, 254);
+        assert_eq!(
+            emu.flags,
+            Flags {
+                zero: false,
+                carry: false,
+                even: true
+            }
+        );
+    }
+
+    #[test]
+    fn compare_and_branch() {
+        let src = |a: u8, cond: &str| {
+            format!(
+                "LDI r1 5\nLIA {}\nCMP r1\nBRC {} [yes]\nLDI r2 1\nHLT\n.yes\nLDI r2 2\nHLT",
+                a, cond
+            )
+        };
+        for (a, cond, taken) in [
+            (3, "LT", true),
+            (5, "LT", false),
+            (5, "LTEQ", true),
+            (7, "GT", true),
+            (5, "GT", false),
+            (5, "GTEQ", true),
+            (5, "EQ", true),
+            (4, "NEQ", true),
+        ] {
+            let emu = run(&src(a, cond));
+            assert_eq!(emu.regs[2], if taken { 2 } else { 1 }, "{} {}", a, cond);
+        }
+    }
+
+    #[test]
+    fn missing_page() {
+        let target = Target {
+            page_count: 16,
+            ..Target::default()
+        };
+        // ICS JMP 20
+        let mut emu = Emulator::new(vec![0x81, 0xa0], &target);
+        let err = EmulatorError::InvalidPage {
+            page: 0,
+            pc: 0,
+            selected: 20,
+        };
+        assert_eq!(emu.run(10), Err(err));
+    }
+
+    #[test]
+    fn far_jumps() {
+        let emu = run("BRC JMP [far]\nHLT\n>1\n.far\nLDI r1 9\nHLT");
+        assert_eq!((emu.page, emu.regs[1]), (1, 9));
+        // not taken, so the page it selected has to be switched back before the next jump
+        let emu = run(
+            "LIA 1\nDEC\nBRC NEQ [far]\nBRC JMP [near]\nHLT\n.near\nLDI r1 7\nHLT\n>1\n.far\nHLT",
+        );
+        assert_eq!((emu.page, emu.regs[1]), (0, 7));
+    }
+}
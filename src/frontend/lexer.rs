@@ -1,24 +1,118 @@
 use logos::{Lexer, Logos};
-use std::process::exit;
+use std::fmt;
 
+use crate::diagnostic::{Diagnostic, Span, Spanned};
 use crate::instr::*;
+use crate::isa;
 
-pub fn register(lex: &mut Lexer<Token>) -> Option<u8> {
-    let mut slice = lex.slice();
-    slice = slice.trim();
-    let mut tmp = slice.to_string();
-    tmp.remove(0);
-    slice = tmp.as_str();
-    Some(
-        slice
-            .parse::<u8>()
-            .unwrap_or_else(|_| panic!("Invalid reg: {}", slice)),
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum LexError {
+    #[default]
+    Unrecognised,
+    InvalidRegister,
+    InvalidImmediate,
+    ImmediateOutOfRange,
+    InvalidPage,
+    UnterminatedString,
+}
+
+impl LexError {
+    pub fn to_diagnostic(&self, slice: &str, span: Span) -> Diagnostic {
+        match self {
+            LexError::Unrecognised => {
+                Diagnostic::error(format!("unrecognised token `{}`", slice), span)
+            }
+            LexError::InvalidRegister => {
+                Diagnostic::error(format!("invalid register `{}`", slice), span)
+                    .with_label("registers are r0-r7 or $0-$7")
+            }
+            LexError::InvalidImmediate => {
+                Diagnostic::error(format!("invalid number `{}`", slice), span)
+                    .with_label("expected a decimal, 0x, 0b, 0o or 'c' literal")
+            }
+            LexError::ImmediateOutOfRange => {
+                Diagnostic::error(format!("`{}` does not fit in a byte", slice), span)
+                    .with_label("immediates range from -128 to 255")
+            }
+            LexError::InvalidPage => {
+                Diagnostic::error(format!("invalid page number `{}`", slice), span)
+                    .with_label("expected `>` followed by a page number")
+            }
+            LexError::UnterminatedString => Diagnostic::error("unterminated string", span)
+                .with_label("expected a closing `\"` on this line"),
+        }
+    }
+}
+
+pub fn register(lex: &mut Lexer<Token>) -> Result<u8, LexError> {
+    match lex.slice().trim()[1..].parse::<u8>() {
+        Ok(r) if r < 8 => Ok(r),
+        _ => Err(LexError::InvalidRegister),
+    }
+}
+
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        c => c,
+    }
+}
+
+// decimal, 0x/0b/0o prefixed or 'c' character literals
+pub fn parse_number(body: &str) -> Result<i64, LexError> {
+    Ok(
+        if let Some(c) = body.strip_prefix('\'').and_then(|b| b.strip_suffix('\'')) {
+            let mut chars = c.chars();
+            let ch = match (chars.next(), chars.next()) {
+                (Some('\\'), Some(e)) => unescape(e),
+                (Some(ch), _) => ch,
+                (None, _) => return Err(LexError::InvalidImmediate),
+            };
+            ch as i64
+        } else {
+            let digits = body.replace('_', "");
+            let (radix, digits) = match digits.get(..2) {
+                Some("0x" | "0X") => (16, &digits[2..]),
+                Some("0b" | "0B") => (2, &digits[2..]),
+                Some("0o" | "0O") => (8, &digits[2..]),
+                _ => (10, &digits[..]),
+            };
+            match i64::from_str_radix(digits, radix) {
+                Ok(v) => v,
+                // all the digits were valid but there were too many of them
+                Err(_) if digits.chars().all(|c| c.is_digit(radix)) && !digits.is_empty() => {
+                    return Err(LexError::ImmediateOutOfRange)
+                }
+                Err(_) => return Err(LexError::InvalidImmediate),
+            }
+        },
     )
 }
 
-pub fn immediate(lex: &mut Lexer<Token>) -> Option<u8> {
-    let slice = lex.slice();
-    Some(slice.parse::<u8>().unwrap())
+pub fn immediate(lex: &mut Lexer<Token>) -> Result<i64, LexError> {
+    parse_number(lex.slice())
+}
+
+pub fn string(lex: &mut Lexer<Token>) -> String {
+    let body = &lex.slice()[1..lex.slice().len() - 1];
+    let mut ret = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => ret.extend(chars.next().map(unescape)),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+pub fn page(lex: &mut Lexer<Token>) -> Result<usize, LexError> {
+    lex.slice()[1..]
+        .parse::<usize>()
+        .map_err(|_| LexError::InvalidPage)
 }
 
 pub fn cond(lex: &mut Lexer<Token>) -> Option<CarbonConds> {
@@ -36,86 +130,210 @@ pub fn cond(lex: &mut Lexer<Token>) -> Option<CarbonConds> {
     }
 }
 
-pub fn instr(lex: &mut Lexer<Token>) -> Option<CarbonInstrVariants> {
-    let slice = lex.slice();
-    Some(match slice.to_uppercase().as_str() {
-        "HLT" => CarbonInstrVariants::Hlt,
-        "ADD" => CarbonInstrVariants::Add,
-        "SUB" => CarbonInstrVariants::Sub,
-        "BSUB" => CarbonInstrVariants::Bsb,
-        "OR" => CarbonInstrVariants::Or,
-        "ADC" => CarbonInstrVariants::Nor,
-        "AND" => CarbonInstrVariants::And,
-        "NAND" => CarbonInstrVariants::Nand,
-        "XOR" => CarbonInstrVariants::Xor,
-        "LIA" => CarbonInstrVariants::Lia,
-        "LDI" => CarbonInstrVariants::Ldi,
-        "ADR" => CarbonInstrVariants::Adr,
-        "RLD" => CarbonInstrVariants::Rld,
-        "RST" => CarbonInstrVariants::Rst,
-        "MST" => CarbonInstrVariants::Mst,
-        "MLD" => CarbonInstrVariants::Mld,
-        "ICS" => CarbonInstrVariants::Ics,
-        "JID" => CarbonInstrVariants::Jid,
-        "BRC" => CarbonInstrVariants::Brc,
-        "CMP" => CarbonInstrVariants::Cmp,
-        "BSR" => CarbonInstrVariants::Bsr,
-        "BSL" => CarbonInstrVariants::Bsl,
-        "PST" => CarbonInstrVariants::Pst,
-        "PLD" => CarbonInstrVariants::Pld,
-        "INC" => CarbonInstrVariants::Inc,
-        "DEC" => CarbonInstrVariants::Dec,
-        "NOP" => CarbonInstrVariants::Nop,
-        _ => {
-            println!("Invalid instruction: {}", slice);
-            exit(-1)
-        }
-    })
+pub fn mnemonic(word: &str) -> Option<CarbonInstrVariants> {
+    isa::by_mnemonic(word).map(|d| d.instr)
 }
 
+// `.name` tokens that are assembler directives rather than labels
+pub const DIRECTIVES: &[&str] = &[
+    "equ", "define", "alias", "macro", "endm", "include", "db", "byte", "ascii", "string", "fill",
+    "zero", "charmap", "org", "align", "section",
+];
+
 #[derive(Debug, PartialEq, Logos, Clone)]
+#[logos(error = LexError)]
+#[logos(skip r"\s+")]
 pub enum Token {
     #[regex("JMP|EQ|NEQ|LT|GTEQ|LTEQ|GT|EVEN", cond, priority = 1)]
     Cond(CarbonConds),
 
     #[regex("\\$[0-9]+", register, priority = 4)]
-    #[regex("(R|r)[0-7]", register, priority = 4)]
+    #[regex("(R|r)[0-9]+", register, priority = 4)]
     Register(u8),
 
-    #[regex("[0-9]+", immediate, priority = 1)]
-    Immediate(u8),
+    #[regex("[0-9][0-9a-zA-Z_]*", immediate, priority = 1)]
+    #[regex(r"'([^'\\]|\\.)'", immediate)]
+    Immediate(i64),
 
-    #[regex("\\w+", instr, priority = 0)]
+    #[regex(r#""([^"\\\n]|\\.)*""#, string)]
+    #[regex(r#""([^"\\\n]|\\.)*"#, |_| Err(LexError::UnterminatedString))]
+    Str(String),
+
+    // never produced by the lexer itself; `tokenise` turns mnemonic identifiers into these
     Instr(CarbonInstrVariants),
 
+    #[regex("\\w+", |lexer| lexer.slice().to_string(), priority = 0)]
+    Ident(String),
+
     #[regex("(#|//).*", |lexer| lexer.slice().to_string())]
     Comment(String),
 
     #[regex(r"\..[^\s]*", |lexer| { let mut s = lexer.slice().to_string(); s.remove(0); s })]
     Label(String),
 
+    // never produced by the lexer itself; `tokenise` turns directive labels into these
+    Directive(String),
+
     #[regex(r"\[\w*\]", |lexer| lexer.slice()[1..lexer.slice().len() - 1].to_string())]
     LabelDeref(String),
 
-    #[regex(r">.[^\s]*", |lexer| lexer.slice()[1..].parse::<usize>().expect("invalid int on pageno"))]
+    #[regex(r">[^\s>]+", page)]
     PageLabel(usize),
+
+    #[token("+", |_| BinOp::Add)]
+    #[token("*", |_| BinOp::Mul)]
+    #[token("/", |_| BinOp::Div)]
+    #[token("%", |_| BinOp::Rem)]
+    #[token("&", |_| BinOp::And)]
+    #[token("|", |_| BinOp::Or)]
+    #[token("^", |_| BinOp::Xor)]
+    #[token("<<", |_| BinOp::Shl)]
+    #[token(">>", |_| BinOp::Shr)]
+    Op(BinOp),
+
+    // binary or unary, so it gets its own token
+    #[token("-")]
+    Minus,
+
+    #[token("~")]
+    Tilde,
+
+    #[token(",")]
+    Comma,
+
+    #[token(":")]
+    Colon,
+
+    #[token("(")]
+    LParen,
+
+    #[token(")")]
+    RParen,
+
+    // stands in for a lexeme the lexer already reported, so the parser doesn't pile on
+    Invalid,
 }
 
-pub fn tokenise(src: &str) -> Vec<Token> {
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Cond(c) => write!(f, "condition `{}`", c),
+            Token::Register(r) => write!(f, "register `r{}`", r),
+            Token::Immediate(i) => write!(f, "immediate `{}`", i),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Instr(i) => write!(f, "instruction `{}`", i),
+            Token::Ident(i) => write!(f, "identifier `{}`", i),
+            Token::Comment(_) => write!(f, "comment"),
+            Token::Label(l) => write!(f, "label `.{}`", l),
+            Token::Directive(d) => write!(f, "directive `.{}`", d),
+            Token::LabelDeref(l) => write!(f, "label reference `[{}]`", l),
+            Token::PageLabel(p) => write!(f, "page marker `>{}`", p),
+            Token::Op(op) => write!(f, "`{}`", op),
+            Token::Minus => write!(f, "`-`"),
+            Token::Tilde => write!(f, "`~`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Colon => write!(f, "`:`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::Invalid => write!(f, "invalid token"),
+        }
+    }
+}
+
+// `base` is where the file starts in the source map; every span is shifted by it
+pub fn tokenise(src: &str, base: usize, diags: &mut Vec<Diagnostic>) -> Vec<Spanned<Token>> {
     let mut lexer = Token::lexer(src);
     let mut ret = Vec::new();
-    'l: loop {
-        let cur_tok = lexer.next();
-        match cur_tok {
-            Some(tok) => ret.push({
-                if let Ok(t) = tok {
-                    t
-                } else {
-                    continue 'l;
-                }
-            }),
-            None => break 'l,
+    while let Some(tok) = lexer.next() {
+        let span = lexer.span().start + base..lexer.span().end + base;
+        match tok {
+            Ok(Token::Ident(word)) => {
+                let tok = mnemonic(&word).map_or(Token::Ident(word), Token::Instr);
+                ret.push(Spanned::new(tok, span));
+            }
+            Ok(Token::Label(name)) if DIRECTIVES.contains(&name.as_str()) => {
+                ret.push(Spanned::new(Token::Directive(name), span));
+            }
+            Ok(t) => ret.push(Spanned::new(t, span)),
+            Err(e) => {
+                diags.push(e.to_diagnostic(lexer.slice(), span.clone()));
+                ret.push(Spanned::new(Token::Invalid, span));
+            }
         }
     }
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        for (src, value) in [
+            ("0", 0),
+            ("255", 255),
+            ("1_000", 1000),
+            ("0x1f", 31),
+            ("0XFF", 255),
+            ("0x_ff", 255),
+            ("0b1010_0101", 0xa5),
+            ("0o17", 15),
+            ("'a'", 97),
+            ("' '", 32),
+            (r"'\n'", 10),
+            (r"'\0'", 0),
+            (r"'\''", 39),
+            (r"'\\'", 92),
+        ] {
+            assert_eq!(parse_number(src), Ok(value), "{}", src);
+        }
+    }
+
+    #[test]
+    fn bad_numbers() {
+        for (src, err) in [
+            ("0x", LexError::InvalidImmediate),
+            ("0b", LexError::InvalidImmediate),
+            ("0b102", LexError::InvalidImmediate),
+            ("0o8", LexError::InvalidImmediate),
+            ("12ab", LexError::InvalidImmediate),
+            ("0xfffffffffffffffff", LexError::ImmediateOutOfRange),
+            ("99999999999999999999", LexError::ImmediateOutOfRange),
+        ] {
+            assert_eq!(parse_number(src), Err(err), "{}", src);
+        }
+    }
+
+    // the lexer reports a bad number rather than panicking, and carries on
+    #[test]
+    fn bad_number_tokens() {
+        let mut diags = Vec::new();
+        let toks = tokenise("LIA 0x\nLIA 0x10", 0, &mut diags);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "invalid number `0x`");
+        assert_eq!(diags[0].span, 4..6);
+        assert_eq!(toks.last().unwrap().node, Token::Immediate(16));
+    }
+
+    // the lexer takes any size of number; whether it fits in a byte is down to
+    // where it's used
+    #[test]
+    fn byte_range() {
+        for (src, ok) in [
+            ("255", true),
+            ("-128", true),
+            ("256", false),
+            ("-129", false),
+        ] {
+            let res = crate::assemble_source(&format!("LIA {}\nHLT", src));
+            match ok {
+                true => assert!(res.is_ok(), "{}", src),
+                false => {
+                    let diags = res.unwrap_err();
+                    assert_eq!(diags[0].message, format!("{} does not fit in a byte", src));
+                }
+            }
+        }
+    }
+}
@@ -1,246 +1,1952 @@
-use std::{collections::HashMap, process::exit};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use crate::diagnostic::{Diagnostic, SourceFile, SourceMap, Span, Spanned};
 use crate::instr::{
-    self, CarbonASMProgram, CarbonInstr, CarbonInstrVariants, CarbonOperand, JmpAddr,
+    BinOp, CarbonASMProgram, CarbonConds, CarbonInstr, CarbonInstrVariants, CarbonOperand, Expr,
+    JmpAddr, UnaryOp,
 };
+use crate::isa::{Operand, Target};
 
-use super::lexer::Token;
+use super::lexer::{tokenise, Token};
 
-fn tok_compare(a: Token, b: Token) -> bool {
-    std::mem::discriminant(&a) == std::mem::discriminant(&b)
+fn tok_compare(a: &Token, b: &Token) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
 }
 
-fn buf_consume(buf: &mut TokenBuffer, toks: &[Token], err: &str) -> Token {
-    for tok in toks {
-        if tok_compare(buf.current(), tok.clone()) {
-            return buf.current();
+fn buf_consume(buf: &mut TokenBuffer, toks: &[Token], err: &str) -> Result<Token, Diagnostic> {
+    match buf.current() {
+        Some(cur) if toks.iter().any(|tok| tok_compare(&cur, tok)) => Ok(cur),
+        _ => Err(buf.expected(err)),
+    }
+}
+
+// jump addresses are stored in the top five bits of their byte
+// a jump operand given as a number; ICS takes a page, everything else an offset
+fn jump_literal(
+    addr: i64,
+    instr: CarbonInstrVariants,
+    target: &Target,
+    span: Span,
+) -> Result<JmpAddr, Diagnostic> {
+    if instr == CarbonInstrVariants::Ics {
+        if !(0..target.page_count as i64).contains(&addr) {
+            return Err(
+                Diagnostic::error(format!("page {} doesn't exist", addr), span).with_label(
+                    format!("`{}` has pages 0 to {}", target.name, target.page_count - 1),
+                ),
+            );
+        }
+        return Ok(JmpAddr::Literal(addr as u8));
+    }
+    let page_size = target.page_size;
+    if !(0..page_size as i64).contains(&addr) {
+        return Err(
+            Diagnostic::error(format!("jump address {} is out of range", addr), span)
+                .with_label(format!("jump addresses range from 0 to {}", page_size - 1)),
+        );
+    }
+    Ok(JmpAddr::Literal(addr as u8))
+}
+
+// negative values are stored as two's complement
+fn to_byte(val: i64, span: Span) -> Result<u8, Diagnostic> {
+    match val {
+        -128..=-1 => Ok(val as i8 as u8),
+        0..=255 => Ok(val as u8),
+        _ => Err(
+            Diagnostic::error(format!("{} does not fit in a byte", val), span)
+                .with_label("immediates range from -128 to 255"),
+        ),
+    }
+}
+
+fn binop(tok: &Token) -> Option<BinOp> {
+    match tok {
+        Token::Op(op) => Some(*op),
+        Token::Minus => Some(BinOp::Sub),
+        _ => None,
+    }
+}
+
+fn precedence(op: BinOp) -> u8 {
+    match op {
+        BinOp::Or => 1,
+        BinOp::Xor => 2,
+        BinOp::And => 3,
+        BinOp::Shl | BinOp::Shr => 4,
+        BinOp::Add | BinOp::Sub => 5,
+        BinOp::Mul | BinOp::Div | BinOp::Rem => 6,
+    }
+}
+
+// statements aren't terminated, so outside of parentheses an expression ends at the
+// end of the line it started on; `line` is that line, or None inside parentheses
+fn parse_binary(buf: &mut TokenBuffer, min: u8, line: Option<Line>) -> Result<Expr, Diagnostic> {
+    let mut lhs = parse_unary(buf)?;
+    while let Some(next) = buf.peek() {
+        let Some(op) = binop(&next.node) else {
+            break;
+        };
+        if precedence(op) < min || line.is_some_and(|l| buf.line_at(buf.pos + 1) != Some(l)) {
+            break;
+        }
+        buf.advance();
+        buf.advance();
+        let rhs = parse_binary(buf, precedence(op) + 1, line)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+fn parse_unary(buf: &mut TokenBuffer) -> Result<Expr, Diagnostic> {
+    let unary = |buf: &mut TokenBuffer, op| {
+        buf.advance();
+        Ok(Expr::Unary(op, Box::new(parse_unary(buf)?)))
+    };
+    match buf.current() {
+        Some(Token::Minus) => unary(buf, UnaryOp::Neg),
+        Some(Token::Tilde) => unary(buf, UnaryOp::Not),
+        Some(Token::Immediate(n)) => Ok(Expr::Num(n)),
+        Some(Token::LabelDeref(l)) => Ok(Expr::Label(l)),
+        Some(Token::Ident(n)) => match buf.symbols.get(&n) {
+            Some((Symbol::Alias(r), _)) => Err(Diagnostic::error(
+                format!("register alias `{}` can't be used as a value", n),
+                buf.span(),
+            )
+            .with_label(format!("`{}` is r{}", n, r))),
+            // a constant, which may not be defined yet, or a label; both are looked
+            // up when the expression is folded
+            _ => Ok(Expr::Name(n)),
+        },
+        Some(Token::LParen) => {
+            buf.advance();
+            let expr = parse_binary(buf, 1, None)?;
+            buf.advance();
+            buf_consume(buf, &[Token::RParen], "expected `)`")?;
+            Ok(expr)
+        }
+        _ => Err(buf.expected("expected a number, label or expression")),
+    }
+}
+
+fn parse_expr(buf: &mut TokenBuffer) -> Result<Expr, Diagnostic> {
+    let line = buf.line_at(buf.pos);
+    parse_binary(buf, 1, line)
+}
+
+// a lone number or label keeps its simple form, so the label pass can still tell
+// which page a jump goes to
+fn parse_jump_addr(
+    buf: &mut TokenBuffer,
+    instr: CarbonInstrVariants,
+) -> Result<JmpAddr, Diagnostic> {
+    let start = buf.span().start;
+    match parse_expr(buf)? {
+        Expr::Num(n) => jump_literal(n, instr, buf.target, start..buf.span().end),
+        // a bare name is as good as `[name]` here
+        Expr::Label(l) | Expr::Name(l) => Ok(JmpAddr::Label(l)),
+        expr => Ok(JmpAddr::Expr(expr)),
+    }
+}
+
+fn parse_data(buf: &mut TokenBuffer) -> Result<CarbonASMProgram, Diagnostic> {
+    let start = buf.span().start;
+    match parse_expr(buf)? {
+        Expr::Num(n) => Ok(CarbonASMProgram::Immediate(to_byte(
+            n,
+            start..buf.span().end,
+        )?)),
+        Expr::Label(l) => Ok(CarbonASMProgram::LabelDeref(l)),
+        expr => Ok(CarbonASMProgram::Expr(expr)),
+    }
+}
+
+// names defined by `.equ`/`.define` and `.alias`; aliases are substituted while
+// parsing, constants wherever they're folded
+enum Symbol {
+    Constant(Expr),
+    Alias(u8),
+}
+
+// the name after a `.equ`/`.alias` style directive
+fn parse_name(buf: &mut TokenBuffer, directive: &str) -> Result<(String, Span), Diagnostic> {
+    let err = &format!("expected a name after .{}", directive);
+    match buf_consume(buf, &[Token::Ident(String::new())], err)? {
+        Token::Ident(name) => Ok((name, buf.span())),
+        _ => unreachable!(),
+    }
+}
+
+// the three bit operand of most instructions; a register, an alias for one, or a
+// constant that fits (PST/PLD use it as a port number)
+fn parse_register(buf: &mut TokenBuffer, instr: CarbonInstrVariants) -> Result<u8, Diagnostic> {
+    let err = &format!("expected register after {}", instr);
+    match buf.current() {
+        Some(Token::Register(r)) => return Ok(r),
+        Some(Token::Ident(n)) => match buf.symbols.get(&n) {
+            Some((Symbol::Alias(r), _)) => return Ok(*r),
+            Some((Symbol::Constant(_), _)) => (),
+            None => return Err(buf.expected(err)),
+        },
+        _ => return Err(buf.expected(err)),
+    }
+    match parse_constant(buf, "operands have to be known while parsing")? {
+        (v @ 0..=7, _) => Ok(v as u8),
+        (v, span) => Err(
+            Diagnostic::error(format!("operand {} is out of range", v), span)
+                .with_label("register and port operands range from 0 to 7"),
+        ),
+    }
+}
+
+// an expression that has to be folded while parsing, so it can't use labels;
+// `why` explains that if it does
+fn parse_constant(buf: &mut TokenBuffer, why: &str) -> Result<(i64, Span), Diagnostic> {
+    let start = buf.span().start;
+    let expr = parse_expr(buf)?;
+    let span = start..buf.span().end;
+    let mut diags = Vec::new();
+    let not_constant = |n: &str, span: &Span, diags: &mut Vec<Diagnostic>| {
+        diags.push(
+            Diagnostic::error(format!("`{}` is not a constant", n), span.clone()).with_label(why),
+        );
+        None
+    };
+    let constants = |n: &str| match buf.symbols.get(n) {
+        Some((Symbol::Constant(value), span)) => Some((value, span)),
+        _ => None,
+    };
+    let names = |n: &str, span: &Span, diags: &mut Vec<Diagnostic>| {
+        constant_value(n, &constants, &not_constant, &[], span, diags)
+    };
+    match eval(&expr, &names, &span, &mut diags) {
+        Some(v) => Ok((v, span)),
+        None => Err(diags.remove(0)),
+    }
+}
+
+// parses `item` over and over for as long as there are commas between them
+fn parse_list(
+    buf: &mut TokenBuffer,
+    mut item: impl FnMut(&mut TokenBuffer) -> Result<(), Diagnostic>,
+) -> Result<(), Diagnostic> {
+    loop {
+        item(buf)?;
+        let line = buf.line_at(buf.pos);
+        match buf.peek() {
+            Some(t) if t.node == Token::Comma && buf.line_at(buf.pos + 1) == line => {
+                buf.advance();
+                buf.next_arg()?;
+            }
+            _ => return Ok(()),
         }
     }
-    println!("{}", err);
-    exit(-1);
 }
 
-struct TokenBuffer {
-    toks: Vec<Token>,
+// `.fill`/`.zero` can't reserve more than the whole address space
+fn parse_count(buf: &mut TokenBuffer) -> Result<usize, Diagnostic> {
+    let max = buf.target.rom_size() as i64;
+    match parse_constant(buf, "counts have to be known while parsing")? {
+        (n @ 0.., _) if n <= max => Ok(n as usize),
+        (n, span) => Err(
+            Diagnostic::error(format!("can't reserve {} bytes", n), span)
+                .with_label(format!("counts range from 0 to {}", max)),
+        ),
+    }
+}
+
+// a string's bytes under the `.charmap` table, or ASCII if there isn't one
+fn encode(buf: &TokenBuffer, text: &str, span: &Span) -> Result<Vec<u8>, Diagnostic> {
+    text.chars()
+        .map(|c| match buf.charmap.get(&c) {
+            Some(b) => Ok(*b),
+            None if buf.charmap.is_empty() && c.is_ascii() => Ok(c as u8),
+            None if buf.charmap.is_empty() => Err(Diagnostic::error(
+                format!("{:?} is not an ASCII character", c),
+                span.clone(),
+            )
+            .with_label("in this string")
+            .with_note("use `.charmap` to give it an encoding")),
+            None => Err(Diagnostic::error(
+                format!("{:?} has no encoding in the character map", c),
+                span.clone(),
+            )
+            .with_label("in this string")),
+        })
+        .collect()
+}
+
+fn parse_string(buf: &mut TokenBuffer, directive: &str) -> Result<(String, Span), Diagnostic> {
+    let err = &format!("expected a string after .{}", directive);
+    match buf_consume(buf, &[Token::Str(String::new())], err)? {
+        Token::Str(text) => Ok((text, buf.span())),
+        _ => unreachable!(),
+    }
+}
+
+// which line a token is on, as (expansion, line start); tokens from the source files
+// are expansion 0 and every macro expansion after that gets its own number, so two
+// expansions next to each other never look like one line
+type Line = (usize, usize);
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(Spanned<Token>, Line)>,
+    // span of the name in the definition, and everything between it and `.endm`
+    span: Span,
+    body_span: Span,
+}
+
+// nested macro calls deeper than this are assumed to be runaway recursion
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+struct TokenBuffer<'a> {
+    toks: Vec<Spanned<Token>>,
+    lines: Vec<Line>,
     pos: usize,
+    eof: usize,
+    sources: &'a mut SourceMap,
+    target: &'a Target,
+    symbols: HashMap<String, (Symbol, Span)>,
+    macros: HashMap<String, Rc<Macro>>,
+    // set up by `.charmap`; strings are plain ASCII while it's empty
+    charmap: HashMap<char, u8>,
 }
 
-impl TokenBuffer {
-    pub fn new(toks: Vec<Token>) -> Self {
-        Self { toks, pos: 0 }
+impl<'a> TokenBuffer<'a> {
+    pub fn new(toks: Vec<Spanned<Token>>, sources: &'a mut SourceMap, target: &'a Target) -> Self {
+        let lines = toks
+            .iter()
+            .map(|t| (0, sources.line_start(t.span.start)))
+            .collect();
+        Self {
+            eof: toks.last().map_or(0, |t| t.span.end),
+            toks,
+            lines,
+            pos: 0,
+            sources,
+            target,
+            symbols: HashMap::new(),
+            macros: HashMap::new(),
+            charmap: HashMap::new(),
+        }
     }
     pub fn has_next(&mut self) -> bool {
         self.pos < self.toks.len()
     }
 
-    pub fn current(&mut self) -> Token {
-        self.toks[self.pos].clone()
+    pub fn current(&mut self) -> Option<Token> {
+        self.toks.get(self.pos).map(|t| t.node.clone())
+    }
+
+    pub fn span(&self) -> Span {
+        self.toks
+            .get(self.pos)
+            .map_or(self.eof..self.eof, |t| t.span.clone())
+    }
+
+    // error for whatever token is under the cursor not being what the parser wanted
+    pub fn expected(&self, err: &str) -> Diagnostic {
+        let found = match self.toks.get(self.pos) {
+            Some(cur) => format!("found {}", cur.node),
+            None => "found end of input".to_string(),
+        };
+        Diagnostic::error(err, self.span()).with_label(found)
+    }
+
+    pub fn peek(&self) -> Option<&Spanned<Token>> {
+        self.toks.get(self.pos + 1)
+    }
+
+    pub fn line_at(&self, pos: usize) -> Option<Line> {
+        self.lines.get(pos).copied()
+    }
+
+    // error recovery; drop everything left on the line the failed statement started on
+    pub fn skip_line(&mut self, line: Option<Line>) {
+        while self.has_next() && self.line_at(self.pos) == line {
+            self.advance();
+        }
+    }
+
+    // operandless instructions followed by a register on the same line are a common slip
+    pub fn skip_stray_operand(&mut self, instr: CarbonInstrVariants, diags: &mut Vec<Diagnostic>) {
+        if let Some(next) = self.toks.get(self.pos + 1) {
+            if matches!(next.node, Token::Register(_))
+                && self.line_at(self.pos) == self.line_at(self.pos + 1)
+            {
+                diags.push(
+                    Diagnostic::warning(format!("{} takes no operand", instr), next.span.clone())
+                        .with_label("this register is ignored"),
+                );
+                self.advance();
+            }
+        }
+    }
+
+    // moves on to the next argument of a directive, which has to be on the same line
+    pub fn next_arg(&mut self) -> Result<(), Diagnostic> {
+        let (prev, line) = (self.span(), self.line_at(self.pos));
+        self.advance();
+        if self.line_at(self.pos) != line {
+            return Err(
+                Diagnostic::error("missing argument", prev).with_label("expected more after this")
+            );
+        }
+        Ok(())
     }
 
     pub fn advance(&mut self) {
         if self.has_next() {
             self.pos += 1;
-        } else {
-            panic!(
-                "Parser called advance without having an extra element on token {:#?}",
-                self.toks[self.pos]
-            )
         }
     }
 
-    pub fn advance_over_skips(&mut self, ret: &mut Vec<CarbonASMProgram>) {
-        while tok_compare(self.current(), Token::Comment(String::new())) {
-            if let Token::Comment(c) = self.current() {
-                ret.push(CarbonASMProgram::Comment(c));
-            }
+    pub fn advance_over_skips(&mut self, ret: &mut Vec<Spanned<CarbonASMProgram>>) {
+        while let Some(Token::Comment(c)) = self.current() {
+            ret.push(Spanned::new(CarbonASMProgram::Comment(c), self.span()));
             self.advance();
         }
     }
 
+    // wraps a node with a span running from `start` to the end of the current token
+    pub fn spanned<T>(&self, start: usize, node: T) -> Spanned<T> {
+        Spanned::new(node, start..self.span().end)
+    }
+
+    pub fn define(&mut self, name: String, sym: Symbol, span: Span) -> Result<(), Diagnostic> {
+        if let Some((_, first)) = self.symbols.get(&name) {
+            return Err(
+                Diagnostic::error(format!("`{}` is defined more than once", name), span)
+                    .with_label("redefined here")
+                    .with_secondary(first.clone(), "first defined here"),
+            );
+        }
+        self.symbols.insert(name, (sym, span));
+        Ok(())
+    }
+
     pub fn get_labels(&mut self) -> Vec<CarbonOperand> {
         let mut ret = Vec::new();
-        while tok_compare(self.current(), Token::Label(String::new())) {
-            match self.current() {
-                Token::Label(l) => ret.push(CarbonOperand::Label(l.to_string())),
-                _ => unreachable!(),
-            }
+        while let Some(Token::Label(l)) = self.current() {
+            ret.push(CarbonOperand::Label(l));
             self.advance();
         }
         ret
     }
 }
 
-pub fn parse(toks: Vec<Token>) -> Vec<CarbonASMProgram> {
+/// Every `.equ`/`.define` constant as written, and the span of its name.
+pub type ConstantMap = HashMap<String, (Expr, Span)>;
+
+pub fn parse(
+    toks: Vec<Spanned<Token>>,
+    sources: &mut SourceMap,
+    target: &Target,
+    diags: &mut Vec<Diagnostic>,
+) -> (Vec<Spanned<CarbonASMProgram>>, ConstantMap) {
     let mut ret = Vec::new();
-    let mut buf = TokenBuffer::new(toks);
-    while buf.has_next() {
+    let mut buf = TokenBuffer::new(toks, sources, target);
+    while let Some(tok) = buf.current() {
+        let line = buf.line_at(buf.pos);
+        let res = match tok {
+            // the expansion replaces the call in place, so parsing carries on from its first token
+            Token::Ident(name) if buf.macros.contains_key(&name) => expand_macro(&mut buf, &name),
+            Token::Directive(d) if d == "include" => include(&mut buf, diags),
+            tok => parse_stmt(&mut buf, tok, &mut ret, diags).map(|()| buf.advance()),
+        };
+        if let Err(diag) = res {
+            // the lexer has already reported whatever is under the cursor
+            if buf.current() != Some(Token::Invalid) {
+                diags.push(diag);
+            }
+            buf.skip_line(line);
+        }
+    }
+    let constants = buf
+        .symbols
+        .into_iter()
+        .filter_map(|(name, (sym, span))| match sym {
+            Symbol::Constant(value) => Some((name, (value, span))),
+            Symbol::Alias(_) => None,
+        })
+        .collect();
+    (ret, constants)
+}
+
+// the name and parameters on a `.macro` line
+fn parse_macro_header(buf: &mut TokenBuffer) -> Result<(String, Span, Vec<String>), Diagnostic> {
+    buf.next_arg()?;
+    let (name, span) = parse_name(buf, "macro")?;
+    let line = buf.line_at(buf.pos);
+    let mut params: Vec<String> = Vec::new();
+    while buf.line_at(buf.pos + 1) == line {
+        buf.advance();
         match buf.current() {
-            Token::Immediate(val) => {
-                ret.push(CarbonASMProgram::Immediate(val));
-            }
-            Token::Instr(val) => {
-                if val == CarbonInstrVariants::Hlt || val == CarbonInstrVariants::Nop {
-                    ret.push(CarbonASMProgram::Instruction(CarbonInstr {
-                        opcode: val,
-                        operand: None,
-                    }))
-                } else if val == CarbonInstrVariants::Ics {
-                    buf.advance();
-                    let cond = match buf_consume(
-                        &mut buf,
-                        &[Token::Cond(instr::CarbonConds::Jmp)],
-                        "Expected cond after ics",
-                    ) {
-                        Token::Cond(c) => c,
-                        _ => unreachable!(),
-                    };
-                    buf.advance();
-                    buf.advance_over_skips(&mut ret);
-                    let mut labels = buf.get_labels();
-
-                    labels.append(&mut vec![
-                        instr::CarbonOperand::Cond(cond),
-                        CarbonOperand::JmpAddr(
-                            match buf_consume(
-                                &mut buf,
-                                &[Token::Immediate(0), Token::LabelDeref(String::new())],
-                                "Expected jump address after jump inst",
-                            ) {
-                                Token::Immediate(a) => JmpAddr::Literal(a),
-                                Token::LabelDeref(a) => JmpAddr::Label(a),
-                                _ => unreachable!(),
-                            },
-                        ),
-                    ]);
-                    ret.push(CarbonASMProgram::Instruction(CarbonInstr {
-                        opcode: CarbonInstrVariants::Ics,
-                        operand: Some(labels),
-                    }))
-                } else if val == CarbonInstrVariants::Brc {
-                    buf.advance();
-                    buf.advance_over_skips(&mut ret);
-                    let cond = match buf_consume(
-                        &mut buf,
-                        &[Token::Cond(instr::CarbonConds::Jmp)],
-                        "Expected cond after brc",
-                    ) {
-                        Token::Cond(c) => c,
-                        _ => unreachable!(),
-                    };
+            Some(Token::Ident(p)) if !params.contains(&p) => params.push(p),
+            Some(Token::Ident(p)) => {
+                return Err(Diagnostic::error(
+                    format!("parameter `{}` is declared more than once", p),
+                    buf.span(),
+                ))
+            }
+            Some(Token::Comment(_)) => break,
+            // parameters can be separated by commas as well as spaces
+            Some(Token::Comma) if !params.is_empty() => (),
+            _ => return Err(buf.expected("expected a parameter name")),
+        }
+    }
+    Ok((name, span, params))
+}
+
+// everything from a `.macro` line to its `.endm`; the cursor is left on the `.endm`.
+// the body is skipped even if the header is bad, so it isn't parsed as plain code
+fn parse_macro(buf: &mut TokenBuffer, diags: &mut Vec<Diagnostic>) -> Result<(), Diagnostic> {
+    let start = buf.span();
+    let line = buf.line_at(buf.pos);
+    let header = parse_macro_header(buf);
+    buf.skip_line(line);
+
+    let mut body = Vec::new();
+    let mut depth = 0;
+    loop {
+        match buf.current() {
+            Some(Token::Directive(d)) if d == "endm" && depth == 0 => break,
+            Some(Token::Directive(d)) if d == "endm" => depth -= 1,
+            Some(Token::Directive(d)) if d == "macro" => depth += 1,
+            Some(_) => (),
+            None => {
+                return Err(Diagnostic::error("macro is never closed", start)
+                    .with_label("expected a matching `.endm` for this"))
+            }
+        }
+        body.push((buf.toks[buf.pos].clone(), buf.lines[buf.pos]));
+        buf.advance();
+    }
+    let (name, span, params) = match header {
+        Ok(header) => header,
+        Err(diag) => {
+            diags.push(diag);
+            return Ok(());
+        }
+    };
+    let body_span = match (body.first(), body.last()) {
+        (Some((first, _)), Some((last, _))) => first.span.start..last.span.end,
+        _ => span.end..span.end,
+    };
+
+    if let Some(first) = buf.macros.get(&name) {
+        diags.push(
+            Diagnostic::error(format!("macro `{}` is defined more than once", name), span)
+                .with_label("redefined here")
+                .with_secondary(first.span.clone(), "first defined here"),
+        );
+        return Ok(());
+    }
+    let mac = Macro {
+        params,
+        body,
+        span,
+        body_span,
+    };
+    buf.macros.insert(name, Rc::new(mac));
+    Ok(())
+}
+
+// replaces an `.include "path"` with the tokens of the file it names; the path is
+// relative to the including file, then each of the include paths in turn
+fn include(buf: &mut TokenBuffer, diags: &mut Vec<Diagnostic>) -> Result<(), Diagnostic> {
+    let start = buf.pos;
+    let directive = buf.span();
+    buf.next_arg()?;
+    let err = "expected a file name after .include";
+    let path = match buf_consume(buf, &[Token::Str(String::new())], err)? {
+        Token::Str(path) => path,
+        _ => unreachable!(),
+    };
+    let span = directive.start..buf.span().end;
+    let end = buf.pos + 1;
+
+    let from = buf.sources.resolve(&span).0;
+    let (file, _) = buf.sources.file(from.start);
+    let dir = Path::new(&file.name).parent().unwrap_or(Path::new(""));
+    let mut candidates = std::iter::once(dir.join(&path))
+        .chain(buf.sources.include_paths.iter().map(|d| d.join(&path)));
+    let Some(found) = candidates.find(|p| p.is_file()) else {
+        let searched: Vec<String> = std::iter::once(dir)
+            .chain(buf.sources.include_paths.iter().map(PathBuf::as_path))
+            .map(|d| match d.as_os_str().is_empty() {
+                true => "`.`".to_string(),
+                false => format!("`{}`", d.display()),
+            })
+            .collect();
+        return Err(Diagnostic::error(format!("can't find `{}`", path), span)
+            .with_label("no such file")
+            .with_note(format!("searched {}", searched.join(", "))));
+    };
+
+    // the including file and everything that included it
+    let chain: Vec<&str> = std::iter::once(file.name.as_str())
+        .chain(
+            buf.sources
+                .include_chain(&from)
+                .into_iter()
+                .map(|(_, s)| buf.sources.file(s.start).0.name.as_str()),
+        )
+        .collect();
+    let canonical = found.canonicalize().ok();
+    if chain
+        .iter()
+        .any(|f| Path::new(f).canonicalize().ok() == canonical)
+    {
+        return Err(Diagnostic::error(
+            format!("`{}` is already being included", found.display()),
+            span,
+        )
+        .with_label("this include is circular"));
+    }
+
+    let src = std::fs::read_to_string(&found)
+        .map_err(|e| Diagnostic::error(format!("can't read `{}`: {}", found.display(), e), span))?;
+    let base = buf.sources.include_file(
+        SourceFile::new(found.display().to_string(), src.clone()),
+        from,
+    );
+    let toks = tokenise(&src, base, diags);
+    let lines: Vec<Line> = toks
+        .iter()
+        .map(|t| (0, buf.sources.line_start(t.span.start)))
+        .collect();
+    buf.lines.splice(start..end, lines);
+    buf.toks.splice(start..end, toks);
+    buf.pos = start;
+    Ok(())
+}
+
+// one macro argument. in a call that separates them with commas it's everything up
+// to the next comma, so it can be any expression; otherwise it's a single token, a
+// parenthesised group, or either behind `-`/`~`
+fn macro_arg(buf: &mut TokenBuffer, commas: bool) -> Vec<Spanned<Token>> {
+    let line = buf.line_at(buf.pos);
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    loop {
+        let tok = buf.toks[buf.pos].clone();
+        match tok.node {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            _ => (),
+        }
+        let prefix = matches!(tok.node, Token::Minus | Token::Tilde | Token::LParen);
+        ret.push(tok);
+        let next = buf.peek().map(|t| &t.node);
+        let done = match commas {
+            true => depth <= 0 && matches!(next, Some(Token::Comma | Token::Comment(_)) | None),
+            false => depth <= 0 && !prefix,
+        };
+        if done || buf.line_at(buf.pos + 1) != line {
+            return ret;
+        }
+        buf.advance();
+    }
+}
+
+// whether the rest of a macro call's line has a comma outside of parentheses
+fn separated_by_commas(buf: &TokenBuffer) -> bool {
+    let line = buf.line_at(buf.pos);
+    let mut depth = 0;
+    for (n, tok) in buf.toks.iter().enumerate().skip(buf.pos + 1) {
+        if buf.line_at(n) != line {
+            break;
+        }
+        match tok.node {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            Token::Comma if depth <= 0 => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+// replaces a macro call with a copy of the body, giving the copy its own spans and lines
+fn expand_macro(buf: &mut TokenBuffer, name: &str) -> Result<(), Diagnostic> {
+    let mac = buf.macros[name].clone();
+    let start = buf.pos;
+    let call_start = buf.span().start;
+    let line = buf.line_at(buf.pos);
+    let commas = separated_by_commas(buf);
+    let mut args: Vec<Vec<Spanned<Token>>> = Vec::new();
+    while buf.line_at(buf.pos + 1) == line
+        && buf
+            .peek()
+            .is_some_and(|t| !tok_compare(&t.node, &Token::Comment(String::new())))
+    {
+        buf.advance();
+        // arguments can be separated by commas as well as spaces
+        if buf.current() == Some(Token::Comma) && !args.is_empty() {
+            continue;
+        }
+        args.push(macro_arg(buf, commas));
+    }
+    let call = call_start..buf.span().end;
+    let end = buf.pos + 1;
+
+    if args.len() != mac.params.len() {
+        let plural = |n: usize| format!("{} argument{}", n, if n == 1 { "" } else { "s" });
+        let were = if args.len() == 1 { "was" } else { "were" };
+        let mut diag = Diagnostic::error(
+            format!(
+                "macro `{}` takes {} but {} {} given",
+                name,
+                plural(mac.params.len()),
+                args.len(),
+                were
+            ),
+            call,
+        )
+        .with_secondary(mac.span.clone(), "macro defined here");
+        // `SCREEN+1` is three arguments when they're separated by spaces
+        let operator = args
+            .iter()
+            .any(|a| matches!(&a[..], [t] if matches!(t.node, Token::Op(_))));
+        if !commas && operator {
+            diag = diag.with_note(
+                "to pass an expression, put it in parentheses or separate the arguments with commas",
+            );
+        }
+        return Err(diag);
+    }
+    if buf.sources.depth(&call) >= MAX_EXPANSION_DEPTH {
+        return Err(Diagnostic::error(
+            format!(
+                "macro `{}` is nested more than {} calls deep",
+                name, MAX_EXPANSION_DEPTH
+            ),
+            call,
+        )
+        .with_label("while expanding this")
+        .with_note("a macro that calls itself never stops expanding"));
+    }
+
+    let (id, base) = buf.sources.expand(name, call, mac.body_span.clone());
+    // labels defined in the body are renamed per expansion so each copy gets its own
+    let locals: Vec<&String> = mac
+        .body
+        .iter()
+        .filter_map(|(t, _)| match &t.node {
+            Token::Label(l) => Some(l),
+            _ => None,
+        })
+        .collect();
+    let local = |l: &String| format!("{}@{}.{}", l, name, id);
+
+    let mut toks = Vec::new();
+    let mut lines = Vec::new();
+    for (tok, (_, line)) in mac.body.iter() {
+        let line = (id + 1, *line);
+        let span = base + (tok.span.start - mac.body_span.start)
+            ..base + (tok.span.end - mac.body_span.start);
+        let node = match &tok.node {
+            Token::Ident(p) if mac.params.contains(p) => {
+                let arg = &args[mac.params.iter().position(|q| q == p).unwrap()];
+                toks.extend(arg.iter().cloned());
+                lines.extend(arg.iter().map(|_| line));
+                continue;
+            }
+            // `[param]` takes the address of the label the argument names
+            Token::LabelDeref(p) if mac.params.contains(p) => {
+                let arg = &args[mac.params.iter().position(|q| q == p).unwrap()];
+                match &arg[..] {
+                    [Spanned {
+                        node: Token::Ident(l) | Token::LabelDeref(l),
+                        span,
+                    }] => {
+                        toks.push(Spanned::new(Token::LabelDeref(l.clone()), span.clone()));
+                        lines.push(line);
+                        continue;
+                    }
+                    _ => {
+                        let arg_span = arg[0].span.start..arg[arg.len() - 1].span.end;
+                        return Err(Diagnostic::error(
+                            format!("`[{}]` needs a label, but this isn't one", p),
+                            arg_span,
+                        )
+                        .with_label("not a label")
+                        .with_secondary(span, "its address is taken here"));
+                    }
+                }
+            }
+            Token::Label(l) if locals.contains(&l) => Token::Label(local(l)),
+            Token::LabelDeref(l) if locals.contains(&l) => Token::LabelDeref(local(l)),
+            Token::Ident(l) if locals.contains(&l) => Token::Ident(local(l)),
+            tok => tok.clone(),
+        };
+        toks.push(Spanned::new(node, span));
+        lines.push(line);
+    }
+    buf.lines.splice(start..end, lines);
+    buf.toks.splice(start..end, toks);
+    buf.pos = start;
+    Ok(())
+}
+
+// whether the last statement is an instruction still waiting for its immediate
+fn wants_immediate(ret: &[Spanned<CarbonASMProgram>], target: &Target) -> bool {
+    let last = ret
+        .iter()
+        .rev()
+        .find(|n| !matches!(n.node, CarbonASMProgram::Comment(_)));
+    match last.map(|n| &n.node) {
+        Some(CarbonASMProgram::Instruction(i)) => target
+            .instr(i.opcode)
+            .is_some_and(|def| def.operands.contains(&Operand::Imm)),
+        _ => false,
+    }
+}
+
+fn parse_stmt(
+    buf: &mut TokenBuffer,
+    tok: Token,
+    ret: &mut Vec<Spanned<CarbonASMProgram>>,
+    diags: &mut Vec<Diagnostic>,
+) -> Result<(), Diagnostic> {
+    let start = buf.span().start;
+    match tok {
+        Token::Immediate(_)
+        | Token::LabelDeref(_)
+        | Token::Minus
+        | Token::Tilde
+        | Token::LParen => {
+            let data = parse_data(buf)?;
+            ret.push(buf.spanned(start, data));
+        }
+        Token::Ident(word) => match buf.symbols.get(&word) {
+            Some((Symbol::Constant(_), _)) => {
+                let data = parse_data(buf)?;
+                ret.push(buf.spanned(start, data));
+            }
+            // an instruction's immediate can name a constant defined further down
+            None if wants_immediate(ret, buf.target) => {
+                let data = parse_data(buf)?;
+                ret.push(buf.spanned(start, data));
+            }
+            sym => {
+                let mut diag =
+                    Diagnostic::error(format!("invalid instruction `{}`", word), buf.span())
+                        .with_label("not a carbon mnemonic");
+                if let Some((Symbol::Alias(r), _)) = sym {
+                    diag = diag.with_note(format!("`{}` is an alias for r{}", word, r));
+                }
+                return Err(diag);
+            }
+        },
+        Token::Directive(d) if d == "equ" || d == "define" => {
+            buf.next_arg()?;
+            let (name, span) = parse_name(buf, &d)?;
+            buf.next_arg()?;
+            let value = parse_expr(buf)?;
+            buf.define(name, Symbol::Constant(value), span)?;
+        }
+        Token::Directive(d) if d == "db" || d == "byte" => {
+            buf.next_arg()?;
+            parse_list(buf, |buf| {
+                let start = buf.span().start;
+                if let Some(Token::Str(text)) = buf.current() {
+                    let span = buf.span();
+                    for b in encode(buf, &text, &span)? {
+                        ret.push(Spanned::new(CarbonASMProgram::Immediate(b), span.clone()));
+                    }
+                } else {
+                    let data = parse_data(buf)?;
+                    ret.push(buf.spanned(start, data));
+                }
+                Ok(())
+            })?;
+        }
+        Token::Directive(d) if d == "ascii" || d == "string" => {
+            buf.next_arg()?;
+            parse_list(buf, |buf| {
+                let (text, span) = parse_string(buf, &d)?;
+                let mut bytes = encode(buf, &text, &span)?;
+                // .string is null terminated
+                if d == "string" {
+                    bytes.push(0);
+                }
+                for b in bytes {
+                    ret.push(Spanned::new(CarbonASMProgram::Immediate(b), span.clone()));
+                }
+                Ok(())
+            })?;
+        }
+        Token::Directive(d) if d == "fill" => {
+            buf.next_arg()?;
+            let n = parse_count(buf)?;
+            buf.next_arg()?;
+            buf_consume(buf, &[Token::Comma], "expected `,` between count and value")?;
+            buf.next_arg()?;
+            let value_start = buf.span().start;
+            let value = parse_data(buf)?;
+            let span = value_start..buf.span().end;
+            ret.extend((0..n).map(|_| Spanned::new(value.clone(), span.clone())));
+        }
+        Token::Directive(d) if d == "zero" => {
+            buf.next_arg()?;
+            let n = parse_count(buf)?;
+            let span = start..buf.span().end;
+            ret.extend((0..n).map(|_| Spanned::new(CarbonASMProgram::Immediate(0), span.clone())));
+        }
+        // `.charmap "chars", first` encodes each character as `first` plus its position
+        Token::Directive(d) if d == "charmap" => {
+            buf.next_arg()?;
+            let (chars, span) = parse_string(buf, &d)?;
+            buf.next_arg()?;
+            buf_consume(buf, &[Token::Comma], "expected `,` after the characters")?;
+            buf.next_arg()?;
+            let (first, first_span) = parse_constant(buf, "codes have to be known while parsing")?;
+            let last = first + chars.chars().count() as i64 - 1;
+            if first < 0 || last > u8::MAX as i64 {
+                return Err(Diagnostic::error(
+                    format!("codes {} to {} don't fit in a byte", first, last),
+                    first_span,
+                )
+                .with_secondary(span, "for these characters"));
+            }
+            for (n, c) in chars.chars().enumerate() {
+                buf.charmap.insert(c, (first + n as i64) as u8);
+            }
+        }
+        Token::Directive(d) if d == "org" => {
+            buf.next_arg()?;
+            let why = "addresses have to be known while parsing";
+            let (first, first_span) = parse_constant(buf, why)?;
+            let line = buf.line_at(buf.pos);
+            let (page, (offset, span)) = match buf.peek() {
+                Some(t) if t.node == Token::Colon && buf.line_at(buf.pos + 1) == line => {
                     buf.advance();
-                    buf.advance_over_skips(&mut ret);
-                    let mut labels = buf.get_labels();
-                    labels.append(&mut vec![
-                        instr::CarbonOperand::Cond(cond),
-                        CarbonOperand::JmpAddr(
-                            match buf_consume(
-                                &mut buf,
-                                &[Token::Immediate(0), Token::LabelDeref(String::new())],
-                                "Expected jump address after jump inst",
-                            ) {
-                                Token::Immediate(a) => JmpAddr::Literal(a),
-                                Token::LabelDeref(a) => JmpAddr::Label(a),
-                                _ => unreachable!(),
-                            },
+                    buf.next_arg()?;
+                    (Some((first, first_span)), parse_constant(buf, why)?)
+                }
+                _ => (None, (first, first_span)),
+            };
+            let page = match page {
+                Some((p @ 0.., _)) if p < buf.target.page_count as i64 => Some(p as usize),
+                Some((p, span)) => {
+                    return Err(
+                        Diagnostic::error(format!("page {} does not exist", p), span).with_label(
+                            format!("pages are numbered 0 to {}", buf.target.page_count - 1),
                         ),
-                    ]);
-                    ret.push(CarbonASMProgram::Instruction(CarbonInstr {
-                        opcode: CarbonInstrVariants::Brc,
-                        operand: Some(labels),
-                    }))
-                } else if val == CarbonInstrVariants::Inc
-                    || val == CarbonInstrVariants::Dec
-                    || val == CarbonInstrVariants::Lia
-                {
-                    ret.push(CarbonASMProgram::Instruction(CarbonInstr {
-                        opcode: val,
-                        operand: None,
-                    }))
-                } else {
+                    )
+                }
+                None => None,
+            };
+            if !(0..buf.target.page_size as i64).contains(&offset) {
+                return Err(Diagnostic::error(
+                    format!("offset {} is outside the page", offset),
+                    span,
+                )
+                .with_label(format!(
+                    "offsets range from 0 to {}",
+                    buf.target.page_size - 1
+                )));
+            }
+            ret.push(buf.spanned(start, CarbonASMProgram::Org(page, offset as usize)));
+        }
+        Token::Directive(d) if d == "section" => {
+            buf.next_arg()?;
+            let (name, span) = parse_name(buf, &d)?;
+            let line = buf.line_at(buf.pos);
+            let page = match buf.peek() {
+                Some(t) if t.node == Token::Comma && buf.line_at(buf.pos + 1) == line => {
                     buf.advance();
-                    buf.advance_over_skips(&mut ret);
-                    let err = &format!("expected address ($NUMBER) got {:?}", buf.current());
-                    let tok = buf_consume(&mut buf, &[Token::Register(0)], err);
-                    let mut instr = CarbonInstr {
-                        opcode: val,
-                        operand: None,
+                    buf.next_arg()?;
+                    match parse_constant(buf, "pages have to be known while parsing")? {
+                        (p @ 0.., _) if p < buf.target.page_count as i64 => Some(p as usize),
+                        (p, span) => {
+                            return Err(Diagnostic::error(
+                                format!("page {} does not exist", p),
+                                span,
+                            )
+                            .with_label(format!(
+                                "pages are numbered 0 to {}",
+                                buf.target.page_count - 1
+                            )))
+                        }
+                    }
+                }
+                _ => None,
+            };
+            ret.push(Spanned::new(CarbonASMProgram::Section(name, page), span));
+        }
+        Token::Directive(d) if d == "align" => {
+            buf.next_arg()?;
+            match parse_constant(buf, "alignments have to be known while parsing")? {
+                (n @ 1.., _) if n <= buf.target.page_size as i64 => {
+                    ret.push(buf.spanned(start, CarbonASMProgram::Align(n as usize)))
+                }
+                (n, span) => {
+                    return Err(Diagnostic::error(format!("can't align to {}", n), span)
+                        .with_label(format!(
+                            "alignments range from 1 to {}",
+                            buf.target.page_size
+                        )))
+                }
+            }
+        }
+        Token::Directive(d) if d == "macro" => parse_macro(buf, diags)?,
+        Token::Directive(d) if d == "endm" => {
+            return Err(Diagnostic::error("`.endm` without a macro", buf.span())
+                .with_label("no `.macro` is open here"))
+        }
+        Token::Directive(d) if d == "alias" => {
+            buf.next_arg()?;
+            let (name, span) = parse_name(buf, &d)?;
+            buf.next_arg()?;
+            let r = match buf.current() {
+                Some(Token::Register(r)) => r,
+                Some(Token::Ident(n)) => match buf.symbols.get(&n) {
+                    Some((Symbol::Alias(r), _)) => *r,
+                    _ => return Err(buf.expected("expected a register to alias")),
+                },
+                _ => return Err(buf.expected("expected a register to alias")),
+            };
+            buf.define(name, Symbol::Alias(r), span)?;
+        }
+        Token::Instr(val) => {
+            let Some(def) = buf.target.instr(val) else {
+                return Err(Diagnostic::error(
+                    format!("{} isn't available on `{}`", val, buf.target.name),
+                    buf.span(),
+                )
+                .with_label("this target doesn't have it"));
+            };
+            let mut operands = Vec::new();
+            let (instr_span, line) = (buf.span(), buf.line_at(buf.pos));
+            for operand in def.operands {
+                // immediates are statements of their own, parsed on the next time round
+                if *operand == Operand::Imm {
+                    continue;
+                }
+                let at = buf.pos;
+                buf.advance();
+                buf.advance_over_skips(ret);
+                // the rest have to be on the same line, or a missing one would take
+                // whatever starts the next
+                if buf.pos == at || buf.line_at(buf.pos) != line {
+                    let what = match operand {
+                        Operand::Reg => "register",
+                        Operand::Cond => "condition",
+                        _ => "jump address",
                     };
-                    match tok {
-                        Token::Register(r) => instr.operand = Some(vec![CarbonOperand::Reg(r)]),
-                        _ => unreachable!(""),
+                    return Err(Diagnostic::error(
+                        format!("expected {} after {}", what, val),
+                        instr_span,
+                    )
+                    .with_label("missing an operand on this line"));
+                }
+                match operand {
+                    Operand::Reg => operands.push(CarbonOperand::Reg(parse_register(buf, val)?)),
+                    Operand::Cond => {
+                        let err = &format!("expected condition after {}", val);
+                        match buf_consume(buf, &[Token::Cond(CarbonConds::Jmp)], err)? {
+                            Token::Cond(c) => operands.push(CarbonOperand::Cond(c)),
+                            _ => unreachable!(),
+                        }
+                    }
+                    Operand::Addr => {
+                        // labels right before the address are defined at this instruction
+                        let mut labels = buf.get_labels();
+                        labels.append(&mut operands);
+                        operands = labels;
+                        operands.push(CarbonOperand::JmpAddr(parse_jump_addr(buf, val)?));
                     }
-                    ret.push(CarbonASMProgram::Instruction(instr));
+                    Operand::Imm => unreachable!(),
+                }
+            }
+            let operands_empty = operands.is_empty();
+            let instr = CarbonInstr {
+                opcode: val,
+                operand: (!operands_empty).then_some(operands),
+            };
+            ret.push(buf.spanned(start, CarbonASMProgram::Instruction(instr)));
+            // nothing else on the line belongs to it
+            if operands_empty {
+                buf.skip_stray_operand(val, diags);
+            }
+        }
+        Token::Comment(c) => ret.push(buf.spanned(start, CarbonASMProgram::Comment(c))),
+        Token::PageLabel(n) => ret.push(buf.spanned(start, CarbonASMProgram::PageLabel(n))),
+        Token::Label(n) => ret.push(buf.spanned(start, CarbonASMProgram::Label(n))),
+        tok => {
+            return Err(Diagnostic::error(format!("unexpected {}", tok), buf.span())
+                .with_label("expected an instruction, label or immediate"))
+        }
+    }
+    Ok(())
+}
+
+// edits to turn `a` into `b`, counting a swap of two neighbouring characters as
+// one, since `strat` for `start` is as likely a typo as any
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j - 1] + cost)
+                .min(d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Where a label ended up.
+#[derive(Debug, Clone)]
+pub struct LabelDef {
+    pub page: usize,
+    /// Offset of the byte before the label, which is what jump addresses encode.
+    pub pc: isize,
+    pub span: Span,
+}
+
+pub type LabelMap = HashMap<String, LabelDef>;
+
+/// What a constant came to once labels were placed.
+#[derive(Debug, Clone)]
+pub struct ConstantDef {
+    pub value: i64,
+    pub span: Span,
+}
+
+/// The value of every constant, for the symbol table. A constant that doesn't
+/// evaluate has already been reported wherever it was used, so it's left out.
+pub fn eval_constants(constants: &ConstantMap, labels: &LabelMap) -> HashMap<String, ConstantDef> {
+    let label = |n: &str, _: &Span, _: &mut Vec<Diagnostic>| labels.get(n).map(|d| d.pc as i64);
+    let lookup = |n: &str| constants.get(n).map(|(expr, span)| (expr, span));
+    constants
+        .keys()
+        .filter_map(|name| {
+            let span = &constants[name].1;
+            let value = constant_value(name, &lookup, &label, &[], span, &mut Vec::new())?;
+            Some((
+                name.clone(),
+                ConstantDef {
+                    value,
+                    span: span.clone(),
+                },
+            ))
+        })
+        .collect()
+}
+
+fn define_label(label_map: &mut LabelMap, name: &str, def: LabelDef, diags: &mut Vec<Diagnostic>) {
+    if let Some(first) = label_map.get(name) {
+        diags.push(
+            Diagnostic::error(
+                format!("label `{}` is defined more than once", name),
+                def.span,
+            )
+            .with_label("redefined here")
+            .with_secondary(first.span.clone(), "first defined here"),
+        );
+    } else {
+        label_map.insert(name.to_string(), def);
+    }
+}
+
+fn resolve_label<'a>(
+    label_map: &'a LabelMap,
+    name: &str,
+    span: &Span,
+    diags: &mut Vec<Diagnostic>,
+) -> Option<&'a LabelDef> {
+    let def = label_map.get(name);
+    if def.is_none() {
+        let mut diag = Diagnostic::error(format!("undefined label `{}`", name), span.clone())
+            .with_label("not defined anywhere");
+        let closest = label_map
+            .keys()
+            .map(|k| (edit_distance(name, k), k))
+            .filter(|(d, _)| *d <= (name.len() / 3).max(1))
+            .min();
+        if let Some((_, k)) = closest {
+            diag = diag.with_note(format!("did you mean `{}`?", k));
+        }
+        diags.push(diag);
+    }
+    def
+}
+
+// the label's offset, reporting it if it's bigger than `max`
+fn label_offset(
+    name: &str,
+    def: &LabelDef,
+    (max, why): (isize, &str),
+    page_size: usize,
+    span: &Span,
+    diags: &mut Vec<Diagnostic>,
+) -> u8 {
+    if def.pc > max {
+        diags.push(
+            Diagnostic::error(
+                format!("label `{}` at offset {} is out of range", name, def.pc + 1),
+                span.clone(),
+            )
+            .with_label(why)
+            .with_secondary(def.span.clone(), "label defined here"),
+        );
+        return 0;
+    }
+    // a label at the start of a page sits at -1, which wraps to the last byte of the
+    // page, as the jump's address wraps back round to the first
+    def.pc.rem_euclid(page_size as isize) as u8
+}
+
+// looks up whatever names are left in an expression, reporting any it can't find
+type NameResolver<'a> = dyn Fn(&str, &Span, &mut Vec<Diagnostic>) -> Option<i64> + 'a;
+
+// a constant's expression and the span of its name, if a name is one
+type ConstantLookup<'a> = dyn Fn(&str) -> Option<(&'a Expr, &'a Span)> + 'a;
+
+// the value of a name, folding it if it's a constant and handing it to `other` if
+// not; `outer` holds the constants already being folded, so a cycle is caught
+fn constant_value(
+    name: &str,
+    constants: &ConstantLookup,
+    other: &NameResolver,
+    outer: &[&str],
+    span: &Span,
+    diags: &mut Vec<Diagnostic>,
+) -> Option<i64> {
+    let Some((expr, def)) = constants(name) else {
+        return other(name, span, diags);
+    };
+    if outer.contains(&name) {
+        diags.push(
+            Diagnostic::error(
+                format!("constant `{}` is defined in terms of itself", name),
+                span.clone(),
+            )
+            .with_label("in this expression")
+            .with_secondary(def.clone(), "defined here"),
+        );
+        return None;
+    }
+    let outer = [outer, &[name]].concat();
+    let names = |n: &str, span: &Span, diags: &mut Vec<Diagnostic>| {
+        constant_value(n, constants, other, &outer, span, diags)
+    };
+    eval(expr, &names, span, diags)
+}
+
+fn eval(expr: &Expr, name: &NameResolver, span: &Span, diags: &mut Vec<Diagnostic>) -> Option<i64> {
+    let err = |msg: &str| Diagnostic::error(msg, span.clone()).with_label("in this expression");
+    match expr {
+        Expr::Num(n) => Some(*n),
+        Expr::Label(l) | Expr::Name(l) => name(l, span, diags),
+        Expr::Unary(op, e) => {
+            let v = eval(e, name, span, diags)?;
+            Some(match op {
+                UnaryOp::Neg => v.wrapping_neg(),
+                UnaryOp::Not => !v,
+            })
+        }
+        Expr::Binary(op, a, b) => {
+            // evaluate both sides so every undefined label gets reported
+            let a = eval(a, name, span, diags);
+            let b = eval(b, name, span, diags);
+            let (a, b) = (a?, b?);
+            let res = match op {
+                BinOp::Add => a.checked_add(b),
+                BinOp::Sub => a.checked_sub(b),
+                BinOp::Mul => a.checked_mul(b),
+                BinOp::Div | BinOp::Rem if b == 0 => {
+                    diags.push(err("division by zero"));
+                    return None;
                 }
+                BinOp::Div => a.checked_div(b),
+                BinOp::Rem => a.checked_rem(b),
+                BinOp::And => Some(a & b),
+                BinOp::Or => Some(a | b),
+                BinOp::Xor => Some(a ^ b),
+                BinOp::Shl => u32::try_from(b).ok().and_then(|b| a.checked_shl(b)),
+                BinOp::Shr => u32::try_from(b).ok().and_then(|b| a.checked_shr(b)),
+            };
+            if res.is_none() {
+                diags.push(err("arithmetic overflow"));
             }
-            Token::Comment(c) => ret.push(CarbonASMProgram::Comment(c)),
-            Token::PageLabel(n) => ret.push(CarbonASMProgram::PageLabel(n)),
-            Token::Label(n) => ret.push(CarbonASMProgram::Label(n)),
-            Token::LabelDeref(label) => ret.push(CarbonASMProgram::LabelDeref(label)),
-            _ => todo!("{:#?}", buf.current()),
+            res
         }
-        if buf.has_next() {
-            buf.advance()
+    }
+}
+
+pub(crate) fn jump_label(instr: &CarbonInstr) -> Option<&String> {
+    instr.operand.iter().flatten().find_map(|o| match o {
+        CarbonOperand::JmpAddr(JmpAddr::Label(n)) => Some(n),
+        _ => None,
+    })
+}
+
+pub(crate) fn jump_cond(instr: &CarbonInstr) -> Option<CarbonConds> {
+    instr.operand.iter().flatten().find_map(|o| match o {
+        CarbonOperand::Cond(c) => Some(c.clone()),
+        _ => None,
+    })
+}
+
+pub(crate) fn ics(addr: JmpAddr) -> CarbonASMProgram {
+    CarbonASMProgram::Instruction(CarbonInstr {
+        opcode: CarbonInstrVariants::Ics,
+        operand: Some(vec![
+            CarbonOperand::Cond(CarbonConds::Jmp),
+            CarbonOperand::JmpAddr(addr),
+        ]),
+    })
+}
+
+// a BRC to a label on another page has to select that page first; this inserts
+// `ICS JMP page` before it and, if the branch might not be taken, switches back after
+fn expand_far_jumps(ast: Vec<Spanned<CarbonASMProgram>>) -> Vec<Spanned<CarbonASMProgram>> {
+    let mut label_pages: HashMap<String, usize> = HashMap::new();
+    let mut page = 0;
+    for node in ast.iter() {
+        match &node.node {
+            CarbonASMProgram::PageLabel(n) | CarbonASMProgram::Org(Some(n), _) => page = *n,
+            CarbonASMProgram::Label(l) => {
+                label_pages.entry(l.clone()).or_insert(page);
+            }
+            CarbonASMProgram::Instruction(i) => {
+                for operand in i.operand.iter().flatten() {
+                    if let CarbonOperand::Label(l) = operand {
+                        label_pages.entry(l.clone()).or_insert(page);
+                    }
+                }
+            }
+            _ => (),
         }
     }
+
+    let mut ret = Vec::new();
+    let mut page = 0;
+    // page picked by an ICS JMP that's still in effect; it lasts until the next jump,
+    // or a label, since code jumping there could have selected anything
+    let mut selected: Option<usize> = None;
+    for node in ast {
+        match &node.node {
+            CarbonASMProgram::PageLabel(n) | CarbonASMProgram::Org(Some(n), _) => {
+                page = *n;
+                selected = None;
+            }
+            CarbonASMProgram::Label(_) => selected = None,
+            CarbonASMProgram::Instruction(i) => {
+                let labelled = i
+                    .operand
+                    .iter()
+                    .flatten()
+                    .any(|o| matches!(o, CarbonOperand::Label(_)));
+                if labelled {
+                    selected = None;
+                }
+                match i.opcode {
+                    CarbonInstrVariants::Ics => {
+                        selected = match (jump_cond(i), i.operand.iter().flatten().last()) {
+                            (Some(CarbonConds::Jmp), Some(CarbonOperand::JmpAddr(a))) => match a {
+                                JmpAddr::Literal(p) => Some(*p as usize),
+                                JmpAddr::Label(l) => label_pages.get(l).copied(),
+                                JmpAddr::Expr(_) => None,
+                            },
+                            _ => None,
+                        }
+                    }
+                    CarbonInstrVariants::Brc => {
+                        let target = jump_label(i).and_then(|l| label_pages.get(l).copied());
+                        let far = target.is_some_and(|t| t != page && Some(t) != selected);
+                        selected = None;
+                        if far {
+                            let label = jump_label(i).unwrap().clone();
+                            let conditional = jump_cond(i) != Some(CarbonConds::Jmp);
+                            let span = node.span.clone();
+                            ret.push(Spanned::new(ics(JmpAddr::Label(label)), span.clone()));
+                            ret.push(node);
+                            if conditional {
+                                ret.push(Spanned::new(ics(JmpAddr::Literal(page as u8)), span));
+                            }
+                            continue;
+                        }
+                    }
+                    CarbonInstrVariants::Jid => selected = None,
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        ret.push(node);
+    }
     ret
 }
 
-pub fn transform_labels(ast: Vec<CarbonASMProgram>) -> Vec<CarbonASMProgram> {
-    // first pass; put label PC positions into a HashMap
-    let mut label_map: HashMap<String, u8> = HashMap::new();
-    let mut pc: i8 = -1;
-    for instr in ast.iter() {
+pub fn transform_labels(
+    ast: Vec<Spanned<CarbonASMProgram>>,
+    constants: &ConstantMap,
+    target: &Target,
+    diags: &mut Vec<Diagnostic>,
+) -> (Vec<Spanned<CarbonASMProgram>>, LabelMap) {
+    let ast = expand_far_jumps(ast);
+    // first pass; put label pages and PC positions into a HashMap
+    let mut label_map: LabelMap = HashMap::new();
+    let mut page = 0;
+    let mut pc: isize = -1;
+    for Spanned { node: instr, span } in ast.iter() {
         match instr {
             CarbonASMProgram::Immediate(_) => pc += 1,
             CarbonASMProgram::Instruction(n) => {
-                pc += 1 + n
-                    .operand
-                    .as_ref()
-                    .map(|e| {
-                        e.iter().fold(0, |acc, elem| {
-                            if let CarbonOperand::JmpAddr(_) = elem {
-                                acc + 1
-                            } else if let CarbonOperand::Label(l) = elem {
-                                label_map.insert(l.clone(), (pc + acc + 1) as u8);
-                                acc
-                            } else {
-                                acc
-                            }
-                        })
-                    })
-                    .unwrap_or(0)
+                pc += 1;
+                for operand in n.operand.iter().flatten() {
+                    match operand {
+                        CarbonOperand::JmpAddr(_) => pc += 1,
+                        CarbonOperand::Label(l) => {
+                            let def = LabelDef {
+                                page,
+                                pc,
+                                span: span.clone(),
+                            };
+                            define_label(&mut label_map, l, def, diags)
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            CarbonASMProgram::LabelDeref(_) | CarbonASMProgram::Expr(_) => pc += 1,
+            CarbonASMProgram::PageLabel(n) => {
+                page = *n;
+                pc = -1;
+            }
+            CarbonASMProgram::Org(p, offset) => {
+                page = p.unwrap_or(page);
+                pc = *offset as isize - 1;
+            }
+            CarbonASMProgram::Align(n) => {
+                let n = *n as isize;
+                pc = (pc + n) / n * n - 1;
+            }
+            CarbonASMProgram::Label(name) => {
+                let def = LabelDef {
+                    page,
+                    pc,
+                    span: span.clone(),
+                };
+                define_label(&mut label_map, name, def, diags)
             }
-            CarbonASMProgram::LabelDeref(_) => pc += 1,
-            CarbonASMProgram::PageLabel(_) => pc = -1,
-
             _ => (),
         }
-        if let CarbonASMProgram::Label(name) = instr {
-            label_map.insert(name.clone(), pc as u8);
-        }
     }
-    println!("{:#?}", label_map);
+    let label = |n: &str, span: &Span, diags: &mut Vec<Diagnostic>| {
+        resolve_label(&label_map, n, span, diags).map(|d| d.pc as i64)
+    };
+    let lookup = |n: &str| constants.get(n).map(|(expr, span)| (expr, span));
+    let label = |n: &str, span: &Span, diags: &mut Vec<Diagnostic>| {
+        constant_value(n, &lookup, &label, &[], span, diags)
+    };
     // second pass, use said map to transform all label refs to the other thingy
-    let mut ret: Vec<CarbonASMProgram> = Vec::new();
-    for instr in ast {
+    let mut ret: Vec<Spanned<CarbonASMProgram>> = Vec::new();
+    for Spanned { node: instr, span } in ast {
         match instr {
-            CarbonASMProgram::LabelDeref(n) => ret.push(CarbonASMProgram::Immediate(label_map[&n])),
-            CarbonASMProgram::Instruction(instr) => {
-                let mut instr_ret = instr.clone();
-                if let Some(operands) = instr.operand {
-                    for (pos, operand) in operands.into_iter().enumerate() {
-                        if let CarbonOperand::JmpAddr(JmpAddr::Label(n)) = operand {
-                            if let Some(ops) = instr_ret.operand.as_deref_mut() {
-                                ops[pos] = CarbonOperand::JmpAddr(JmpAddr::Literal(label_map[&n]));
-                            }
+            CarbonASMProgram::LabelDeref(n) => {
+                let max = (u8::MAX as isize, "immediates are a single byte");
+                let addr = resolve_label(&label_map, &n, &span, diags).map_or(0, |def| {
+                    label_offset(&n, def, max, target.page_size, &span, diags)
+                });
+                ret.push(Spanned::new(CarbonASMProgram::Immediate(addr), span))
+            }
+            CarbonASMProgram::Expr(e) => {
+                let val = eval(&e, &label, &span, diags).unwrap_or(0);
+                let byte = to_byte(val, span.clone()).unwrap_or_else(|d| {
+                    diags.push(d);
+                    0
+                });
+                ret.push(Spanned::new(CarbonASMProgram::Immediate(byte), span))
+            }
+            CarbonASMProgram::Instruction(mut instr) => {
+                let opcode = instr.opcode;
+                for operand in instr.operand.iter_mut().flatten() {
+                    // a bare name parses as a label, but it could be a constant
+                    if let CarbonOperand::JmpAddr(JmpAddr::Label(n)) = operand {
+                        if constants.contains_key(n) {
+                            *operand = CarbonOperand::JmpAddr(JmpAddr::Expr(Expr::Name(n.clone())));
                         }
                     }
+                    if let CarbonOperand::JmpAddr(JmpAddr::Expr(e)) = operand {
+                        // labels can fold to -1 at the start of a page, which wraps like they do
+                        let addr = match eval(e, &label, &span, diags) {
+                            Some(-1) if opcode != CarbonInstrVariants::Ics => {
+                                JmpAddr::Literal(target.page_size as u8 - 1)
+                            }
+                            Some(v) => jump_literal(v, opcode, target, span.clone())
+                                .unwrap_or_else(|d| {
+                                    diags.push(d);
+                                    JmpAddr::Literal(0)
+                                }),
+                            None => JmpAddr::Literal(0),
+                        };
+                        *operand = CarbonOperand::JmpAddr(addr);
+                    }
+                    if let CarbonOperand::JmpAddr(JmpAddr::Label(n)) = operand {
+                        let def = resolve_label(&label_map, n, &span, diags);
+                        let addr = match def {
+                            // ICS selects a page, so a label there means the page it's on
+                            Some(def) if opcode == CarbonInstrVariants::Ics => def.page as u8,
+                            Some(def) => {
+                                let max = (
+                                    target.page_size as isize - 1,
+                                    "jump addresses only reach one page",
+                                );
+                                label_offset(n, def, max, target.page_size, &span, diags)
+                            }
+                            None => 0,
+                        };
+                        *operand = CarbonOperand::JmpAddr(JmpAddr::Literal(addr));
+                    }
                 }
-                ret.push(CarbonASMProgram::Instruction(instr_ret));
+                ret.push(Spanned::new(CarbonASMProgram::Instruction(instr), span));
             }
             CarbonASMProgram::Label(_) => (),
-            _ => ret.push(instr),
+            _ => ret.push(Spanned::new(instr, span)),
         }
     }
-    ret
+    (ret, label_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what `expand_far_jumps` makes of `src`, as mnemonics, labels and page markers;
+    // ICS shows the page it selects
+    fn far_jumps(src: &str) -> Vec<String> {
+        let mut sources = SourceMap::new();
+        let base = sources.add_file(SourceFile::new("test.carbon", src));
+        let mut diags = Vec::new();
+        let toks = tokenise(src, base, &mut diags);
+        let (ast, _) = parse(toks, &mut sources, &Target::default(), &mut diags);
+        assert!(diags.is_empty(), "{:?}", diags);
+        expand_far_jumps(ast)
+            .into_iter()
+            .filter_map(|n| match n.node {
+                CarbonASMProgram::Instruction(i) if i.opcode == CarbonInstrVariants::Ics => match i
+                    .operand
+                    .iter()
+                    .flatten()
+                    .last()
+                {
+                    Some(CarbonOperand::JmpAddr(JmpAddr::Literal(p))) => Some(format!("ICS {}", p)),
+                    Some(CarbonOperand::JmpAddr(JmpAddr::Label(l))) => Some(format!("ICS [{}]", l)),
+                    _ => Some("ICS".to_string()),
+                },
+                CarbonASMProgram::Instruction(i) => Some(i.opcode.to_string()),
+                CarbonASMProgram::Label(l) => Some(format!(".{}", l)),
+                CarbonASMProgram::PageLabel(p) => Some(format!(">{}", p)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // folds an expression with no names in it
+    fn fold(src: &str) -> (Option<i64>, Vec<Diagnostic>) {
+        let mut sources = SourceMap::new();
+        let base = sources.add_file(SourceFile::new("test.carbon", src));
+        let mut diags = Vec::new();
+        let toks = tokenise(src, base, &mut diags);
+        let target = Target::default();
+        let mut buf = TokenBuffer::new(toks, &mut sources, &target);
+        let expr = parse_expr(&mut buf).unwrap();
+        let names = |_: &str, _: &Span, _: &mut Vec<Diagnostic>| None;
+        let value = eval(&expr, &names, &(0..src.len()), &mut diags);
+        (value, diags)
+    }
+
+    #[test]
+    fn precedence() {
+        for (src, value) in [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("7 % 4 * 2", 6),
+            ("1 << 2 + 1", 8),
+            ("1 | 2 ^ 3 & 4", 3),
+            ("6 & 3 << 1", 6),
+            ("-2 * 3", -6),
+            ("~0 & 0xff", 255),
+            ("--1", 1),
+        ] {
+            assert_eq!(fold(src), (Some(value), vec![]), "{}", src);
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        for (src, message) in [
+            ("4611686018427387904 * 2", "arithmetic overflow"),
+            ("1 << 64", "arithmetic overflow"),
+            ("1 >> -1", "arithmetic overflow"),
+            ("1 / 0", "division by zero"),
+            ("1 % (2 - 2)", "division by zero"),
+        ] {
+            let (value, diags) = fold(src);
+            assert_eq!(value, None, "{}", src);
+            assert_eq!(diags.len(), 1, "{}", src);
+            assert_eq!(diags[0].message, message, "{}", src);
+        }
+    }
+
+    fn bytes(src: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        crate::assemble_source(src).map(|image| image.bytes())
+    }
+
+    #[test]
+    fn constants_before_their_definition() {
+        let src = "LIA\nX + 1\n.equ X 5\nLDI r1 Y\nBRC JMP Y\n.equ Y Z + 1\n.equ Z 2\nHLT";
+        assert_eq!(bytes(src), bytes("LIA 6\nLDI r1 3\nBRC JMP 3\nHLT"));
+    }
+
+    #[test]
+    fn macro_param_address() {
+        let src = ".macro go dest\nBRC JMP [dest]\n.endm\ngo here\nHLT\n.here\nHLT";
+        assert_eq!(bytes(src), bytes("BRC JMP [here]\nHLT\n.here\nHLT"));
+        let diags = bytes(".macro go dest\nBRC JMP [dest]\n.endm\ngo 5").unwrap_err();
+        assert_eq!(
+            diags[0].message,
+            "`[dest]` needs a label, but this isn't one"
+        );
+    }
+
+    #[test]
+    fn macro_expression_args() {
+        let mac = ".macro li r v\nLDI r v\n.endm\n.equ SCREEN 4\n";
+        let expected = bytes("LDI r2 5\nLDI r3 9");
+        let src = format!("{}li r2, SCREEN+1\nli r3, (SCREEN + 1) * 2 - 1", mac);
+        assert_eq!(bytes(&src), expected);
+        assert_eq!(
+            bytes(&format!("{}li r2 (SCREEN+1)\nli r3 9", mac)),
+            expected
+        );
+
+        let diags = bytes(&format!("{}li r2 SCREEN+1", mac)).unwrap_err();
+        assert_eq!(
+            diags[0].message,
+            "macro `li` takes 2 arguments but 4 were given"
+        );
+        assert_eq!(
+            diags[0].notes,
+            ["to pass an expression, put it in parentheses or separate the arguments with commas"]
+        );
+    }
+
+    #[test]
+    fn operands_stay_on_their_line() {
+        let diags = bytes("ADD\nLIA 5").unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "expected register after ADD");
+        assert_eq!(diags[0].span, 0..3);
+        let diags = bytes("BRC JMP\n3").unwrap_err();
+        assert_eq!(diags[0].message, "expected jump address after BRC");
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(edit_distance("strat", "start"), 1);
+        assert_eq!(edit_distance("loop", "lop"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+
+        let diags = bytes(".start\nBRC JMP [strat]").unwrap_err();
+        assert_eq!(diags[0].message, "undefined label `strat`");
+        assert_eq!(diags[0].notes, ["did you mean `start`?"]);
+        // a five letter name only gets one edit, and this takes two
+        assert_eq!(edit_distance("strtx", "start"), 2);
+        let diags = bytes(".start\nBRC JMP [strtx]").unwrap_err();
+        assert_eq!(diags[0].message, "undefined label `strtx`");
+        assert!(diags[0].notes.is_empty());
+    }
+
+    #[test]
+    fn duplicate_label() {
+        let diags = bytes(".loop\nHLT\n.loop\nHLT").unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "label `loop` is defined more than once");
+        assert_eq!(diags[0].span, 10..15);
+        assert_eq!(
+            diags[0].secondary,
+            [(0..5, "first defined here".to_string())]
+        );
+    }
+
+    #[test]
+    fn data_directives() {
+        let src = ".equ N 3
+.db 1, 2, -1, \"ok\"
+.byte 0x10
+.fill N, 0xAA
+.zero 2
+.ascii \"hi\", \"!\"
+.string \"A\"
+.charmap \"0123456789\", 0
+.string \"42\"";
+        let expected = [
+            1, 2, 255, b'o', b'k', 0x10, 0xaa, 0xaa, 0xaa, 0, 0, b'h', b'i', b'!', b'A', 0, 4, 2, 0,
+        ];
+        let image = bytes(src).unwrap();
+        assert_eq!(image[..expected.len()], expected);
+        assert!(image[expected.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn bad_data_directives() {
+        for (src, message) in [
+            (".db 256", "256 does not fit in a byte"),
+            (".ascii \"caf\u{e9}\"", "'\u{e9}' is not an ASCII character"),
+            (
+                ".charmap \"ab\", 1\n.ascii \"abc\"",
+                "'c' has no encoding in the character map",
+            ),
+            (
+                ".charmap \"ab\", 255",
+                "codes 255 to 256 don't fit in a byte",
+            ),
+            (".fill -1, 0", "can't reserve -1 bytes"),
+            (".fill 2 0", "expected `,` between count and value"),
+            (".zero 100000", "can't reserve 100000 bytes"),
+        ] {
+            let diags = bytes(src).unwrap_err();
+            assert_eq!(diags[0].message, message, "{}", src);
+        }
+    }
+
+    #[test]
+    fn constant_cycle() {
+        let diags = bytes("LIA X\n.equ X Y + 1\n.equ Y X\nHLT").unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(
+            diags[0].message,
+            "constant `X` is defined in terms of itself"
+        );
+    }
+
+    #[test]
+    fn ics_pages_exist() {
+        let build = |page_size, page_count| {
+            let target = Target {
+                page_size,
+                page_count,
+                ..Target::default()
+            };
+            let options = crate::Options {
+                target,
+                ..Default::default()
+            };
+            let file = SourceFile::new("test.carbon", "ICS JMP 20\nHLT");
+            crate::assemble_file(&mut SourceMap::new(), file, &options).map(|_| ())
+        };
+        let diags = build(32, 16).unwrap_err();
+        assert_eq!(diags[0].message, "page 20 doesn't exist");
+        // smaller pages don't mean fewer of them
+        assert!(build(16, 32).is_ok());
+    }
+
+    #[test]
+    fn page_start_wraps_to_the_last_byte() {
+        let target = Target {
+            page_size: 16,
+            ..Target::default()
+        };
+        let options = crate::Options {
+            target,
+            ..Default::default()
+        };
+        let src = ">1\n.top\nHLT\nBRC JMP [top]\nBRC JMP [top] + 0";
+        let file = SourceFile::new("test.carbon", src);
+        let image = crate::assemble_file(&mut SourceMap::new(), file, &options).unwrap();
+        assert_eq!(image.bytes()[16..21], [0xf8, 0x91, 15 << 3, 0x91, 15 << 3]);
+    }
+
+    #[test]
+    fn far_jump_selects_the_page() {
+        assert_eq!(
+            far_jumps(">0\nBRC JMP [far]\n>1\n.far\nHLT"),
+            [">0", "ICS [far]", "BRC", ">1", ".far", "HLT"]
+        );
+    }
+
+    #[test]
+    fn conditional_far_jump_switches_back() {
+        assert_eq!(
+            far_jumps(">2\nBRC EQ [far]\nHLT\n>1\n.far\nHLT"),
+            [
+                ">2",
+                "ICS [far]",
+                "BRC",
+                "ICS 2",
+                "HLT",
+                ">1",
+                ".far",
+                "HLT"
+            ]
+        );
+    }
+
+    #[test]
+    fn near_jump_is_left_alone() {
+        assert_eq!(far_jumps(".top\nBRC EQ [top]"), [".top", "BRC"]);
+    }
+
+    #[test]
+    fn selection_lasts_until_a_jump() {
+        // the ICS is still in effect at the BRC, so nothing needs adding
+        assert_eq!(
+            far_jumps(">0\nICS JMP 1\nADD r1\nBRC JMP [far]\n>1\n.far\nHLT"),
+            [">0", "ICS 1", "ADD", "BRC", ">1", ".far", "HLT"]
+        );
+        // but not past one
+        assert_eq!(
+            far_jumps(">0\nICS JMP 1\nJID r1\nBRC JMP [far]\n>1\n.far\nHLT"),
+            [
+                ">0",
+                "ICS 1",
+                "JID",
+                "ICS [far]",
+                "BRC",
+                ">1",
+                ".far",
+                "HLT"
+            ]
+        );
+    }
+
+    #[test]
+    fn selection_ends_at_a_label() {
+        assert_eq!(
+            far_jumps(">0\nICS JMP 1\n.here\nBRC JMP [far]\n>1\n.far\nHLT"),
+            [
+                ">0",
+                "ICS 1",
+                ".here",
+                "ICS [far]",
+                "BRC",
+                ">1",
+                ".far",
+                "HLT"
+            ]
+        );
+    }
+
+    // writes `files` into a fresh directory under the system temp dir, which is
+    // removed again when it's dropped
+    struct TempTree(PathBuf);
+
+    impl TempTree {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("carbon-test-{}-{}", name, std::process::id()));
+            for (path, src) in files {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, src).unwrap();
+            }
+            Self(root)
+        }
+
+        fn assemble(&self, file: &str, include_paths: &[&str]) -> Result<Vec<u8>, Vec<Diagnostic>> {
+            let mut sources = SourceMap::new();
+            sources.include_paths = include_paths.iter().map(|d| self.0.join(d)).collect();
+            let path = self.0.join(file);
+            let src = std::fs::read_to_string(&path).unwrap();
+            let file = SourceFile::new(path.display().to_string(), src);
+            crate::assemble_file(&mut sources, file, &crate::Options::default())
+                .map(|image| image.bytes())
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn include_search_order() {
+        let tree = TempTree::new(
+            "include-search",
+            &[
+                (
+                    "src/main.carbon",
+                    ".include \"lib.carbon\"\n.include \"util.carbon\"\nHLT",
+                ),
+                ("src/lib.carbon", "LIA 1"),
+                ("lib/lib.carbon", "LIA 2"),
+                ("lib/util.carbon", ".include \"inner.carbon\""),
+                ("lib/inner.carbon", "LIA 3"),
+            ],
+        );
+        // the including file's directory comes first, and a file found on the
+        // include path looks beside itself for what it includes
+        assert_eq!(
+            tree.assemble("src/main.carbon", &["lib"]),
+            bytes("LIA 1\nLIA 3\nHLT")
+        );
+
+        let diags = tree.assemble("src/main.carbon", &[]).unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "can't find `util.carbon`");
+        assert_eq!(
+            diags[0].notes,
+            [format!("searched `{}`", tree.0.join("src").display())]
+        );
+    }
+
+    #[test]
+    fn include_cycle() {
+        let tree = TempTree::new(
+            "include-cycle",
+            &[
+                ("a.carbon", ".include \"b.carbon\"\nHLT"),
+                ("b.carbon", "LIA 1\n.include \"sub/c.carbon\""),
+                ("sub/c.carbon", ".include \"../a.carbon\""),
+            ],
+        );
+        let diags = tree.assemble("a.carbon", &[]).unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert!(
+            diags[0]
+                .message
+                .ends_with("a.carbon` is already being included"),
+            "{}",
+            diags[0].message
+        );
+        assert_eq!(diags[0].label.as_deref(), Some("this include is circular"));
+
+        // including the same file twice, one after the other, is fine
+        let tree = TempTree::new(
+            "include-twice",
+            &[
+                (
+                    "main.carbon",
+                    ".include \"one.carbon\"\n.include \"one.carbon\"\nHLT",
+                ),
+                ("one.carbon", "LIA 1"),
+            ],
+        );
+        assert_eq!(
+            tree.assemble("main.carbon", &[]),
+            bytes("LIA 1\nLIA 1\nHLT")
+        );
+    }
 }
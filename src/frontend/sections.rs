@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+
+use crate::diagnostic::{Diagnostic, Span, Spanned};
+use crate::instr::{
+    CarbonASMProgram, CarbonConds, CarbonInstr, CarbonInstrVariants, CarbonOperand, JmpAddr,
+};
+
+use crate::isa::{self, Operand, Target};
+
+use super::parser::{ics, jump_cond, jump_label};
+
+/// Where a `.section` ended up.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    // the first of its pages; it has more than one if it was split
+    pub page: usize,
+    pub pages: usize,
+    pub pinned: bool,
+    // the first `.section` line for it
+    pub span: Span,
+}
+
+// whether a node puts bytes in the image
+fn emits(node: &CarbonASMProgram) -> bool {
+    matches!(
+        node,
+        CarbonASMProgram::Instruction(_)
+            | CarbonASMProgram::Immediate(_)
+            | CarbonASMProgram::LabelDeref(_)
+            | CarbonASMProgram::Expr(_)
+    )
+}
+
+/// Gives every `.section` a page of its own. Everything in a section, wherever it
+/// appears, is gathered up and moved behind a page marker for its page, with a label
+/// of the section's name at the start, so `ICS JMP [name]` selects it. Pinned sections
+/// get the page they asked for; the rest take the lowest pages nothing else uses.
+/// Code outside any section keeps its place; a `>n` marker ends the current section.
+///
+/// With `split` set, a section too long for one page is spread over consecutive
+/// pages, each one ending with a jump to the start of the next.
+pub fn place_sections(
+    ast: Vec<Spanned<CarbonASMProgram>>,
+    split: bool,
+    target: &Target,
+    diags: &mut Vec<Diagnostic>,
+) -> (Vec<Spanned<CarbonASMProgram>>, Vec<Section>) {
+    let page_count = target.page_count;
+    let mut top = Vec::new();
+    let mut sections: Vec<(Section, Vec<Spanned<CarbonASMProgram>>)> = Vec::new();
+    let mut current: Option<usize> = None;
+    // pages claimed by page markers and `.org`, and what claimed them
+    let mut used: HashMap<usize, Span> = HashMap::new();
+    let mut marked = false;
+    for node in ast {
+        match &node.node {
+            CarbonASMProgram::Section(name, page) => {
+                match sections.iter().position(|(s, _)| s.name == *name) {
+                    Some(i) => {
+                        let first = &mut sections[i].0;
+                        match (first.pinned, page) {
+                            (true, Some(p)) if *p != first.page => diags.push(
+                                Diagnostic::error(
+                                    format!("section `{}` is pinned to two pages", name),
+                                    node.span.clone(),
+                                )
+                                .with_label(format!("pinned to page {} here", p))
+                                .with_secondary(
+                                    first.span.clone(),
+                                    format!("but to page {} here", first.page),
+                                ),
+                            ),
+                            (false, Some(p)) => {
+                                first.page = *p;
+                                first.pinned = true;
+                            }
+                            _ => (),
+                        }
+                        current = Some(i);
+                    }
+                    None => {
+                        let section = Section {
+                            name: name.clone(),
+                            page: page.unwrap_or(0),
+                            pages: 1,
+                            pinned: page.is_some(),
+                            span: node.span.clone(),
+                        };
+                        sections.push((section, vec![]));
+                        current = Some(sections.len() - 1);
+                    }
+                }
+                continue;
+            }
+            CarbonASMProgram::PageLabel(n) => {
+                current = None;
+                marked = true;
+                used.entry(*n).or_insert(node.span.clone());
+            }
+            CarbonASMProgram::Org(Some(n), _) => {
+                marked |= current.is_none();
+                used.entry(*n).or_insert(node.span.clone());
+            }
+            // code before any page marker goes on page 0, like it always has
+            n if emits(n) && current.is_none() && !marked => {
+                used.entry(0).or_insert(node.span.clone());
+            }
+            _ => (),
+        }
+        match current {
+            Some(i) => sections[i].1.push(node),
+            None => top.push(node),
+        }
+    }
+
+    let mut sections: Vec<(Section, Vec<Vec<Spanned<CarbonASMProgram>>>)> = sections
+        .into_iter()
+        .map(|(mut section, body)| {
+            let chunks = match split {
+                true => split_section(body, target.page_size),
+                false => vec![body],
+            };
+            section.pages = chunks.len();
+            (section, chunks)
+        })
+        .collect();
+
+    let free = |used: &HashMap<usize, Span>, page: usize, n: usize| {
+        page + n <= page_count && (page..page + n).all(|p| !used.contains_key(&p))
+    };
+    for (section, _) in sections.iter().filter(|(s, _)| s.pinned) {
+        let pages = section.page..section.page + section.pages;
+        if free(&used, section.page, section.pages) {
+            used.extend(pages.map(|p| (p, section.span.clone())));
+            continue;
+        }
+        let mut diag = Diagnostic::error(
+            format!(
+                "section `{}` doesn't fit at page {}",
+                section.name, section.page
+            ),
+            section.span.clone(),
+        )
+        .with_label(format!(
+            "pinned here, needing {} page{}",
+            section.pages,
+            if section.pages == 1 { "" } else { "s" }
+        ));
+        if let Some(first) = pages.clone().find_map(|p| used.get(&p)) {
+            diag = diag.with_secondary(first.clone(), "but a page it needs is used here");
+        } else {
+            diag = diag.with_note(format!("pages are numbered 0 to {}", page_count - 1));
+        }
+        diags.push(diag);
+    }
+    for (section, _) in sections.iter_mut().filter(|(s, _)| !s.pinned) {
+        match (0..page_count).find(|p| free(&used, *p, section.pages)) {
+            Some(page) => {
+                section.page = page;
+                used.extend((page..page + section.pages).map(|p| (p, section.span.clone())));
+            }
+            None => diags.push(
+                Diagnostic::error(
+                    format!("no free page left for section `{}`", section.name),
+                    section.span.clone(),
+                )
+                .with_label(match section.pages {
+                    1 => "it needs a page of its own".to_string(),
+                    n => format!("it needs {} pages in a row", n),
+                }),
+            ),
+        }
+    }
+
+    let mut ret = top;
+    let mut placed = Vec::new();
+    for (section, chunks) in sections {
+        let span = section.span.clone();
+        let last = chunks.len() - 1;
+        for (n, chunk) in chunks.into_iter().enumerate() {
+            let page = section.page + n;
+            ret.push(Spanned::new(
+                CarbonASMProgram::PageLabel(page),
+                span.clone(),
+            ));
+            if n == 0 {
+                let label = CarbonASMProgram::Label(section.name.clone());
+                ret.push(Spanned::new(label, span.clone()));
+            }
+            ret.extend(chunk);
+            if n != last {
+                ret.extend(
+                    continuation(page + 1, target.page_size)
+                        .map(|node| Spanned::new(node, span.clone())),
+                );
+            }
+        }
+        placed.push(section);
+    }
+    (ret, placed)
+}
+
+// bytes at the end of every page of a split section: `ICS JMP next` and `BRC JMP`
+const CONTINUATION_SIZE: usize = 4;
+
+fn continuation(page: usize, page_size: usize) -> [CarbonASMProgram; 2] {
+    [
+        ics(JmpAddr::Literal(page as u8)),
+        // jump addresses name the byte before the target, which wraps for offset 0
+        CarbonASMProgram::Instruction(CarbonInstr {
+            opcode: CarbonInstrVariants::Brc,
+            operand: Some(vec![
+                CarbonOperand::Cond(CarbonConds::Jmp),
+                CarbonOperand::JmpAddr(JmpAddr::Literal(page_size as u8 - 1)),
+            ]),
+        }),
+    ]
+}
+
+// labels a node defines
+fn labels(node: &CarbonASMProgram) -> Vec<&String> {
+    match node {
+        CarbonASMProgram::Label(l) => vec![l],
+        CarbonASMProgram::Instruction(i) => i
+            .operand
+            .iter()
+            .flatten()
+            .filter_map(|o| match o {
+                CarbonOperand::Label(l) => Some(l),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// bytes a node takes on page `chunk` once `expand_far_jumps` has been over it, given
+// which page each of the section's labels is on; anything else is on another page
+fn size(node: &CarbonASMProgram, chunk: usize, chunks: &HashMap<&String, usize>) -> usize {
+    match node {
+        CarbonASMProgram::Instruction(i) => {
+            let operands = i.operand.iter().flatten();
+            let mut n = 1 + operands
+                .filter(|o| matches!(o, CarbonOperand::JmpAddr(_)))
+                .count();
+            let far = jump_label(i).is_some_and(|l| chunks.get(l) != Some(&chunk));
+            if i.opcode == CarbonInstrVariants::Brc && far {
+                n += 2;
+                if jump_cond(i) != Some(CarbonConds::Jmp) {
+                    n += 2;
+                }
+            }
+            n
+        }
+        // worst case, since it depends on where it ends up
+        CarbonASMProgram::Align(n) => n - 1,
+        n if emits(n) => 1,
+        _ => 0,
+    }
+}
+
+// runs of nodes that have to stay on the same page: anything that takes no space
+// goes with the node after it, instructions taking an immediate keep it and ICS
+// keeps the jump it picks the page for
+fn units(body: Vec<Spanned<CarbonASMProgram>>) -> Vec<Vec<Spanned<CarbonASMProgram>>> {
+    let mut ret = Vec::new();
+    let mut unit = Vec::new();
+    for node in body {
+        let emitted = emits(&node.node) || matches!(node.node, CarbonASMProgram::Align(_));
+        let holds = matches!(
+            &node.node,
+            CarbonASMProgram::Instruction(i) if i.opcode == CarbonInstrVariants::Ics
+                || isa::get(i.opcode).operands.contains(&Operand::Imm)
+        );
+        unit.push(node);
+        if emitted && !holds {
+            ret.push(std::mem::take(&mut unit));
+        }
+    }
+    if !unit.is_empty() {
+        ret.push(unit);
+    }
+    ret
+}
+
+// which page of the section each label in it is on
+fn label_chunks(chunks: &[Vec<Spanned<CarbonASMProgram>>]) -> HashMap<String, usize> {
+    chunks
+        .iter()
+        .enumerate()
+        .flat_map(|(n, chunk)| {
+            chunk
+                .iter()
+                .flat_map(|node| labels(&node.node))
+                .map(move |l| (l.clone(), n))
+        })
+        .collect()
+}
+
+// splits the units greedily; every page but the last keeps room for the continuation
+fn chunk(
+    units: &[Vec<Spanned<CarbonASMProgram>>],
+    chunks_of: &HashMap<String, usize>,
+    page_size: usize,
+) -> Vec<Vec<Spanned<CarbonASMProgram>>> {
+    let chunks_of: HashMap<&String, usize> = chunks_of.iter().map(|(k, v)| (k, *v)).collect();
+    let unit_size = |unit: &[Spanned<CarbonASMProgram>], chunk: usize| -> usize {
+        unit.iter().map(|n| size(&n.node, chunk, &chunks_of)).sum()
+    };
+    let mut ret = vec![Vec::new()];
+    let mut used = 0;
+    for (n, unit) in units.iter().enumerate() {
+        let chunk = ret.len() - 1;
+        let size = unit_size(unit, chunk);
+        let rest: usize = units[n..].iter().map(|u| unit_size(u, chunk)).sum();
+        let fits = used + size <= page_size - CONTINUATION_SIZE || used + rest <= page_size;
+        if !fits && used > 0 {
+            ret.push(Vec::new());
+            used = unit_size(unit, chunk + 1);
+        } else {
+            used += size;
+        }
+        ret.last_mut().unwrap().extend(unit.iter().cloned());
+    }
+    ret
+}
+
+// a section's body cut into page sized pieces. how big a jump is depends on whether
+// its label ends up on the same page, so this repeats until the labels stop moving;
+// if they never settle every jump is taken to be far, which can only waste space.
+// sections using `.org` are left alone since their offsets are fixed
+fn split_section(
+    body: Vec<Spanned<CarbonASMProgram>>,
+    page_size: usize,
+) -> Vec<Vec<Spanned<CarbonASMProgram>>> {
+    if body
+        .iter()
+        .any(|n| matches!(n.node, CarbonASMProgram::Org(..)))
+    {
+        return vec![body];
+    }
+    let units = units(body);
+    let mut chunks_of: HashMap<String, usize> = units
+        .iter()
+        .flatten()
+        .flat_map(|n| labels(&n.node))
+        .map(|l| (l.clone(), 0))
+        .collect();
+    for _ in 0..8 {
+        let chunks = chunk(&units, &chunks_of, page_size);
+        let moved = label_chunks(&chunks);
+        if moved == chunks_of {
+            return chunks;
+        }
+        chunks_of = moved;
+    }
+    chunk(&units, &HashMap::new(), page_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::{SourceFile, SourceMap};
+    use crate::frontend::{lexer::tokenise, parser::parse};
+
+    fn body(src: &str) -> Vec<Spanned<CarbonASMProgram>> {
+        let mut sources = SourceMap::new();
+        let base = sources.add_file(SourceFile::new("test.carbon", src));
+        let mut diags = Vec::new();
+        let toks = tokenise(src, base, &mut diags);
+        let (ast, _) = parse(toks, &mut sources, &Target::default(), &mut diags);
+        assert!(diags.is_empty(), "{:?}", diags);
+        ast
+    }
+
+    // each piece as mnemonics, immediates and labels
+    fn show(pieces: Vec<Vec<Spanned<CarbonASMProgram>>>) -> Vec<String> {
+        pieces
+            .into_iter()
+            .map(|piece| {
+                let nodes: Vec<String> = piece
+                    .into_iter()
+                    .filter_map(|n| match n.node {
+                        CarbonASMProgram::Instruction(i) => Some(i.opcode.to_string()),
+                        CarbonASMProgram::Immediate(n) => Some(n.to_string()),
+                        CarbonASMProgram::Label(l) => Some(format!(".{}", l)),
+                        _ => None,
+                    })
+                    .collect();
+                nodes.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn units_keep_operands_together() {
+        let src = "ICS JMP 1\nBRC JMP 3\nHLT\nLDI r1 5\nLDI r2 6\n.l\nHLT";
+        assert_eq!(
+            show(units(body(src))),
+            ["ICS BRC", "HLT", "LDI 5", "LDI 6", ".l HLT"]
+        );
+    }
+
+    #[test]
+    fn split_immediate_runs() {
+        let src = "LDI r1 5\n".repeat(20) + "HLT";
+        let pieces = show(split_section(body(&src), 32));
+        // 14 pairs fill the 28 bytes left before the continuation
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0], ["LDI 5"; 14].join(" "));
+        assert_eq!(pieces[1], ["LDI 5"; 6].join(" ") + " HLT");
+    }
+
+    #[test]
+    fn split_keeps_ics_with_its_jump() {
+        // 4 bytes a pair, so 7 of them fill the 28 bytes left before the continuation,
+        // and the HLT after the last doesn't come along with it
+        let src = "ICS JMP 1\nBRC JMP 3\n".repeat(8) + "HLT";
+        let pieces = show(split_section(body(&src), 32));
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0], ["ICS BRC"; 7].join(" "));
+        assert_eq!(pieces[1], "ICS BRC HLT");
+    }
+
+    #[test]
+    fn split_section_assembles() {
+        let src = ".section big\n".to_string() + &"LDI r1 5\n".repeat(20) + "HLT";
+        let options = crate::Options {
+            split_pages: true,
+            ..Default::default()
+        };
+        let file = SourceFile::new("test.carbon", src);
+        let image = crate::assemble_file(&mut SourceMap::new(), file, &options).unwrap();
+        assert_eq!(image.sections[0].pages, 2);
+    }
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+
+use crate::isa;
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum CarbonInstrVariants {
     Hlt,
@@ -29,6 +33,12 @@ pub enum CarbonInstrVariants {
     Nop,
 }
 
+impl fmt::Display for CarbonInstrVariants {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", isa::get(*self).mnemonic)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum CarbonConds {
     Even = 0,
@@ -41,6 +51,22 @@ pub enum CarbonConds {
     Lteq,
 }
 
+impl fmt::Display for CarbonConds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cond = match self {
+            CarbonConds::Even => "EVEN",
+            CarbonConds::Jmp => "JMP",
+            CarbonConds::Eq => "EQ",
+            CarbonConds::Neq => "NEQ",
+            CarbonConds::Lt => "LT",
+            CarbonConds::Gt => "GT",
+            CarbonConds::Gteq => "GTEQ",
+            CarbonConds::Lteq => "LTEQ",
+        };
+        write!(f, "{}", cond)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum CarbonOperand {
     Cond(CarbonConds),
@@ -49,10 +75,61 @@ pub enum CarbonOperand {
     Label(String),
 }
 
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Xor => "^",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+// constant expression, folded once label addresses are known
+#[derive(PartialEq, Debug, Clone)]
+pub enum Expr {
+    Num(i64),
+    // `[name]`
+    Label(String),
+    // bare `name`
+    Name(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum JmpAddr {
     Literal(u8),
     Label(String),
+    Expr(Expr),
 }
 
 impl JmpAddr {
@@ -77,5 +154,12 @@ pub enum CarbonASMProgram {
     Comment(String),
     Label(String),
     PageLabel(usize),
+    // `.org page:offset`, or `.org offset` within the current page
+    Org(Option<usize>, usize),
+    // `.align n`; skips ahead to the next offset that's a multiple of n
+    Align(usize),
+    // `.section name`, optionally pinned to a page; replaced by `place_sections`
+    Section(String, Option<usize>),
     LabelDeref(String),
+    Expr(Expr),
 }
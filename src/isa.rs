@@ -0,0 +1,329 @@
+//! The carbon instruction set as a table. Everything that needs to know a
+//! mnemonic, an encoding or what an instruction takes reads it from [`ISA`], or
+//! from the [`Target`] being assembled for when it depends on the hardware.
+
+use std::path::Path;
+
+use crate::backend::assembler::{PAGE_COUNT, PAGE_SIZE};
+use crate::instr::CarbonInstrVariants;
+
+/// Something an instruction takes after its mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    /// A register, in the low three bits of the opcode byte.
+    Reg,
+    /// A branch condition, in the low three bits of the opcode byte.
+    Cond,
+    /// A jump address, in a byte of its own.
+    Addr,
+    /// An immediate, written as the next statement in the source.
+    Imm,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstrDef {
+    pub instr: CarbonInstrVariants,
+    pub mnemonic: &'static str,
+    /// The top five bits; operands fill in the rest.
+    pub opcode: u8,
+    pub operands: &'static [Operand],
+    pub description: &'static str,
+}
+
+impl InstrDef {
+    /// Bytes the instruction takes, counting an immediate after it.
+    pub fn size(&self) -> usize {
+        1 + self
+            .operands
+            .iter()
+            .filter(|o| matches!(o, Operand::Addr | Operand::Imm))
+            .count()
+    }
+
+    /// The operands as they'd appear in a usage line, e.g. `BRC <cond> <addr>`.
+    pub fn usage(&self) -> String {
+        let mut ret = self.mnemonic.to_string();
+        for operand in self.operands {
+            ret.push_str(match operand {
+                Operand::Reg => " <reg>",
+                Operand::Cond => " <cond>",
+                Operand::Addr => " <addr>",
+                Operand::Imm => " <imm>",
+            });
+        }
+        ret
+    }
+}
+
+use CarbonInstrVariants as I;
+use Operand::*;
+
+#[rustfmt::skip]
+pub const ISA: &[InstrDef] = &[
+    InstrDef { instr: I::Nop,  mnemonic: "NOP",  opcode: 0b00000000, operands: &[],           description: "Do nothing" },
+    InstrDef { instr: I::Add,  mnemonic: "ADD",  opcode: 0b00001000, operands: &[Reg],        description: "Add a register to the accumulator" },
+    InstrDef { instr: I::Sub,  mnemonic: "SUB",  opcode: 0b00010000, operands: &[Reg],        description: "Subtract a register from the accumulator" },
+    InstrDef { instr: I::Bsb,  mnemonic: "BSUB", opcode: 0b00011000, operands: &[Reg],        description: "Subtract the accumulator from a register, into the accumulator" },
+    InstrDef { instr: I::Or,   mnemonic: "OR",   opcode: 0b00100000, operands: &[Reg],        description: "Bitwise OR a register into the accumulator" },
+    InstrDef { instr: I::Nor,  mnemonic: "ADC",  opcode: 0b00101000, operands: &[Reg],        description: "Add a register and the carry flag to the accumulator" },
+    InstrDef { instr: I::And,  mnemonic: "AND",  opcode: 0b00110000, operands: &[Reg],        description: "Bitwise AND a register into the accumulator" },
+    InstrDef { instr: I::Nand, mnemonic: "NAND", opcode: 0b00111000, operands: &[Reg],        description: "Bitwise NAND a register into the accumulator" },
+    InstrDef { instr: I::Xor,  mnemonic: "XOR",  opcode: 0b01000000, operands: &[Reg],        description: "Bitwise XOR a register into the accumulator" },
+    InstrDef { instr: I::Lia,  mnemonic: "LIA",  opcode: 0b01001000, operands: &[Imm],        description: "Load the next byte into the accumulator" },
+    InstrDef { instr: I::Ldi,  mnemonic: "LDI",  opcode: 0b01010000, operands: &[Reg, Imm],   description: "Load the next byte into a register" },
+    InstrDef { instr: I::Adr,  mnemonic: "ADR",  opcode: 0b01011000, operands: &[Reg],        description: "Set the memory address to a register" },
+    InstrDef { instr: I::Rld,  mnemonic: "RLD",  opcode: 0b01100000, operands: &[Reg],        description: "Load a register into the accumulator" },
+    InstrDef { instr: I::Rst,  mnemonic: "RST",  opcode: 0b01101000, operands: &[Reg],        description: "Store the accumulator in a register" },
+    InstrDef { instr: I::Mst,  mnemonic: "MST",  opcode: 0b01110000, operands: &[Reg],        description: "Store a register at the memory address" },
+    InstrDef { instr: I::Mld,  mnemonic: "MLD",  opcode: 0b01111000, operands: &[Reg],        description: "Load the byte at the memory address into a register" },
+    InstrDef { instr: I::Ics,  mnemonic: "ICS",  opcode: 0b10000000, operands: &[Cond, Addr], description: "Select the page the next jump goes to, if the condition holds" },
+    InstrDef { instr: I::Jid,  mnemonic: "JID",  opcode: 0b10001000, operands: &[Reg],        description: "Jump to the address in a register" },
+    InstrDef { instr: I::Brc,  mnemonic: "BRC",  opcode: 0b10010000, operands: &[Cond, Addr], description: "Jump if the condition holds" },
+    InstrDef { instr: I::Dec,  mnemonic: "DEC",  opcode: 0b10011000, operands: &[],           description: "Decrement the accumulator" },
+    InstrDef { instr: I::Cmp,  mnemonic: "CMP",  opcode: 0b10100000, operands: &[Reg],        description: "Set the flags from the accumulator minus a register" },
+    InstrDef { instr: I::Bsr,  mnemonic: "BSR",  opcode: 0b10101000, operands: &[Reg],        description: "Shift the accumulator right by a register" },
+    InstrDef { instr: I::Bsl,  mnemonic: "BSL",  opcode: 0b10110000, operands: &[Reg],        description: "Shift the accumulator left by a register" },
+    InstrDef { instr: I::Pst,  mnemonic: "PST",  opcode: 0b10111000, operands: &[Reg],        description: "Write the accumulator to a port" },
+    InstrDef { instr: I::Pld,  mnemonic: "PLD",  opcode: 0b11000000, operands: &[Reg],        description: "Read a port into the accumulator" },
+    InstrDef { instr: I::Inc,  mnemonic: "INC",  opcode: 0b11001000, operands: &[],           description: "Increment the accumulator" },
+    InstrDef { instr: I::Hlt,  mnemonic: "HLT",  opcode: 0b11111000, operands: &[],           description: "Stop the clock" },
+];
+
+pub fn get(instr: CarbonInstrVariants) -> &'static InstrDef {
+    ISA.iter().find(|d| d.instr == instr).unwrap()
+}
+
+/// Any instruction some target has; mnemonics are matched without regard to case.
+pub fn by_mnemonic(word: &str) -> Option<&'static InstrDef> {
+    ISA.iter().find(|d| d.mnemonic.eq_ignore_ascii_case(word))
+}
+
+/// The built in targets, for listing in errors and help.
+pub const TARGETS: &[&str] = &["carbon"];
+
+/// A hardware build to assemble for: how big its ROM is and which instructions
+/// it has, with their encodings.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub name: String,
+    pub page_size: usize,
+    pub page_count: usize,
+    pub isa: Vec<InstrDef>,
+}
+
+impl Default for Target {
+    /// The current carbon, with every instruction in [`ISA`].
+    fn default() -> Self {
+        Target {
+            name: "carbon".to_string(),
+            page_size: PAGE_SIZE,
+            page_count: PAGE_COUNT,
+            isa: ISA.to_vec(),
+        }
+    }
+}
+
+impl Target {
+    pub fn builtin(name: &str) -> Option<Target> {
+        match name {
+            "carbon" => Some(Target::default()),
+            _ => None,
+        }
+    }
+
+    /// A target named on the command line: either a built in one or a TOML profile.
+    pub fn find(name: &str) -> Result<Target, String> {
+        if let Some(target) = Target::builtin(name) {
+            return Ok(target);
+        }
+        let path = Path::new(name);
+        if path.extension().is_some_and(|e| e == "toml") {
+            let src = std::fs::read_to_string(path)
+                .map_err(|e| format!("can't read `{}`: {}", name, e))?;
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            return Target::from_toml(&stem, &src).map_err(|e| format!("in `{}`: {}", name, e));
+        }
+        Err(format!(
+            "unknown target `{}`; expected one of {} or a `.toml` profile",
+            name,
+            TARGETS
+                .iter()
+                .map(|t| format!("`{}`", t))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+
+    /// Reads a profile made of changes to another target, named `name` unless it
+    /// says otherwise:
+    ///
+    /// ```toml
+    /// base = "carbon"     # the target this one starts from
+    /// page_count = 16
+    /// remove = ["ADC"]    # instructions the hardware doesn't have
+    ///
+    /// [opcodes]           # encodings that moved
+    /// HLT = 0b11110000
+    /// ```
+    pub fn from_toml(name: &str, src: &str) -> Result<Target, String> {
+        let table: toml::Table = src
+            .parse()
+            .map_err(|e: toml::de::Error| e.message().to_string())?;
+        // a misspelt key would otherwise leave the base target's setting in place
+        const KEYS: [&str; 6] = [
+            "base",
+            "name",
+            "page_size",
+            "page_count",
+            "remove",
+            "opcodes",
+        ];
+        if let Some(key) = table.keys().find(|k| !KEYS.contains(&k.as_str())) {
+            return Err(format!(
+                "unknown key `{}`; expected one of {}",
+                key,
+                KEYS.map(|k| format!("`{}`", k)).join(", ")
+            ));
+        }
+        let string = |key: &str| match table.get(key) {
+            Some(toml::Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("`{}` has to be a string", key)),
+            None => Ok(None),
+        };
+        let size = |key: &str, default: usize, max: usize| match table.get(key) {
+            Some(toml::Value::Integer(n)) if (1..=max as i64).contains(n) => Ok(*n as usize),
+            Some(_) => Err(format!("`{}` has to be a number from 1 to {}", key, max)),
+            None => Ok(default),
+        };
+
+        let base = string("base")?.unwrap_or_else(|| "carbon".to_string());
+        let mut target = Target::builtin(&base)
+            .ok_or_else(|| format!("`base` names `{}`, which isn't a built in target", base))?;
+        target.name = string("name")?.unwrap_or_else(|| name.to_string());
+        // jump addresses and page selects are five bits, so neither can go past 32
+        target.page_size = size("page_size", target.page_size, PAGE_SIZE)?;
+        target.page_count = size("page_count", target.page_count, PAGE_COUNT)?;
+
+        let known = |m: &str, target: &Target| {
+            target
+                .by_mnemonic(m)
+                .map(|d| d.instr)
+                .ok_or_else(|| format!("`{}` isn't an instruction on `{}`", m, base))
+        };
+        match table.get("remove") {
+            Some(toml::Value::Array(list)) => {
+                for m in list {
+                    let m = m.as_str().ok_or("`remove` has to be a list of mnemonics")?;
+                    let instr = known(m, &target)?;
+                    target.isa.retain(|d| d.instr != instr);
+                }
+            }
+            Some(_) => return Err("`remove` has to be a list of mnemonics".to_string()),
+            None => (),
+        }
+        match table.get("opcodes") {
+            Some(toml::Value::Table(opcodes)) => {
+                for (m, opcode) in opcodes {
+                    let instr = known(m, &target)?;
+                    let opcode = match opcode.as_integer() {
+                        Some(n @ 0..=255) if n & 0b111 == 0 => n as u8,
+                        _ => {
+                            return Err(format!(
+                            "the opcode for `{}` has to be a byte with its low three bits clear",
+                            m
+                        ))
+                        }
+                    };
+                    target
+                        .isa
+                        .iter_mut()
+                        .find(|d| d.instr == instr)
+                        .unwrap()
+                        .opcode = opcode;
+                }
+            }
+            Some(_) => return Err("`opcodes` has to be a table".to_string()),
+            None => (),
+        }
+        for (n, def) in target.isa.iter().enumerate() {
+            if let Some(other) = target.isa[..n].iter().find(|d| d.opcode == def.opcode) {
+                return Err(format!(
+                    "`{}` and `{}` both have the opcode {:#010b}",
+                    other.mnemonic, def.mnemonic, def.opcode
+                ));
+            }
+        }
+        Ok(target)
+    }
+
+    /// `None` if the target doesn't have the instruction.
+    pub fn instr(&self, instr: CarbonInstrVariants) -> Option<&InstrDef> {
+        self.isa.iter().find(|d| d.instr == instr)
+    }
+
+    pub fn by_mnemonic(&self, word: &str) -> Option<&InstrDef> {
+        self.isa
+            .iter()
+            .find(|d| d.mnemonic.eq_ignore_ascii_case(word))
+    }
+
+    /// The instruction an opcode byte holds, ignoring its low three bits.
+    pub fn decode(&self, word: u8) -> Option<&InstrDef> {
+        self.isa.iter().find(|d| d.opcode == word & 0b11111000)
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.page_size * self.page_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_profile() {
+        let src = include_str!("../examples/profile.toml");
+        let target = Target::from_toml("profile", src).unwrap();
+        assert_eq!(target.name, "carbon-example");
+        assert_eq!((target.page_size, target.page_count), (PAGE_SIZE, 16));
+        assert!(target.by_mnemonic("ADC").is_none());
+        assert_eq!(target.by_mnemonic("HLT").unwrap().opcode, 0b11010000);
+        assert_eq!(target.decode(0b11010000).unwrap().mnemonic, "HLT");
+        // unchanged ones keep their encoding
+        assert_eq!(target.by_mnemonic("BRC").unwrap().opcode, 0b10010000);
+    }
+
+    #[test]
+    fn bad_profiles() {
+        for (src, err) in [
+            (
+                "base = \"z80\"",
+                "`base` names `z80`, which isn't a built in target",
+            ),
+            (
+                "page_count = 33",
+                "`page_count` has to be a number from 1 to 32",
+            ),
+            (
+                "remove = [\"FOO\"]",
+                "`FOO` isn't an instruction on `carbon`",
+            ),
+            (
+                "[opcodes]\nHLT = 1",
+                "the opcode for `HLT` has to be a byte with its low three bits clear",
+            ),
+            (
+                "[opcodes]\nHLT = 0",
+                "`NOP` and `HLT` both have the opcode 0b00000000",
+            ),
+            (
+                "page_cout = 16",
+                "unknown key `page_cout`; expected one of `base`, `name`, `page_size`, \
+                 `page_count`, `remove`, `opcodes`",
+            ),
+        ] {
+            assert_eq!(Target::from_toml("test", src).unwrap_err(), err);
+        }
+    }
+}
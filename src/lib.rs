@@ -0,0 +1,75 @@
+//! Assembler for the carbon CPU.
+//!
+//! The pipeline is `tokenise` -> `parse` -> `place_sections` -> `transform_labels`
+//! -> `assemble`;
+//! `assemble_source` runs all of it and hands back either the page image or
+//! every diagnostic produced along the way.
+
+pub mod backend;
+pub mod diagnostic;
+pub mod emulator;
+pub mod frontend;
+pub mod instr;
+pub mod isa;
+pub mod lsp;
+
+pub use backend::assembler::{assemble, Image, PageOutput};
+pub use backend::disassembler::disassemble;
+pub use backend::listing::listing;
+pub use backend::symbols::{symbols_json, symbols_text};
+pub use diagnostic::{Diagnostic, Level, SourceFile, SourceMap, Span, Spanned};
+pub use frontend::lexer::tokenise;
+pub use frontend::parser::{eval_constants, parse, transform_labels, ConstantDef, LabelDef};
+pub use frontend::sections::{place_sections, Section};
+pub use isa::Target;
+
+/// Settings for a build that don't come from the source itself.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Spread sections too long for one page over several; see [`place_sections`].
+    pub split_pages: bool,
+    /// The hardware to assemble for.
+    pub target: Target,
+}
+
+/// Assembles a whole program with the default [`Options`]. Warnings are carried on
+/// the returned [`Image`]; if anything is an error, all diagnostics (warnings
+/// included) come back as `Err`. Spans are byte offsets into `src`, apart from those
+/// inside macro expansions; use [`assemble_file`] to keep the [`SourceMap`] that
+/// renders them.
+pub fn assemble_source(src: &str) -> Result<Image, Vec<Diagnostic>> {
+    let file = SourceFile::new("<input>", src);
+    assemble_file(&mut SourceMap::new(), file, &Options::default())
+}
+
+/// Like [`assemble_source`], for a named file built with `options`. The file is
+/// added to `sources`, which is needed to render the diagnostics and to find
+/// anything it `.include`s.
+pub fn assemble_file(
+    sources: &mut SourceMap,
+    file: SourceFile,
+    options: &Options,
+) -> Result<Image, Vec<Diagnostic>> {
+    let mut diags = Vec::new();
+    let src = file.src.clone();
+    let base = sources.add_file(file);
+    let toks = tokenise(&src, base, &mut diags);
+    let (ast, constants) = parse(toks, sources, &options.target, &mut diags);
+    if diagnostic::error_count(&diags) > 0 {
+        return Err(diags);
+    }
+    let (ast, sections) = place_sections(ast, options.split_pages, &options.target, &mut diags);
+    let (ast, labels) = transform_labels(ast, &constants, &options.target, &mut diags);
+    let constants = eval_constants(&constants, &labels);
+    let image = assemble(ast, &options.target, &mut diags);
+    if diagnostic::error_count(&diags) > 0 {
+        return Err(diags);
+    }
+    Ok(Image {
+        sections,
+        labels,
+        constants,
+        warnings: diags,
+        ..image
+    })
+}
@@ -0,0 +1,212 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use super::protocol::{path_to_uri, range, uri_to_path};
+use crate::backend::assembler::{assemble, Image};
+use crate::diagnostic::{self, Diagnostic, Level, SourceFile, SourceMap, Span, Spanned};
+use crate::frontend::lexer::{tokenise, Token};
+use crate::frontend::parser::{parse, transform_labels, LabelMap};
+use crate::frontend::sections::place_sections;
+use crate::isa::{InstrDef, Operand};
+use crate::Options;
+
+/// One run of the assembler over an open document, kept until it changes.
+pub struct Analysis {
+    pub uri: String,
+    pub text: String,
+    pub sources: SourceMap,
+    // the document's own tokens; it's the first file in `sources`, so these spans
+    // are offsets into `text`
+    pub tokens: Vec<Spanned<Token>>,
+    pub diags: Vec<Diagnostic>,
+    pub labels: LabelMap,
+    pub image: Image,
+}
+
+impl Analysis {
+    /// Runs every pass, even after errors, so labels and page usage stay up to date
+    /// while a line is half typed. Past a parse error the later passes only see what
+    /// parsed, so what they report is left out rather than piled on.
+    pub fn new(uri: &str, text: String, options: &Options, include_paths: &[PathBuf]) -> Self {
+        let name = uri_to_path(uri).map_or_else(|| uri.to_string(), |p| p.display().to_string());
+        let mut sources = SourceMap::new();
+        sources.include_paths = include_paths.to_vec();
+        let base = sources.add_file(SourceFile::new(name, text.clone()));
+
+        let mut diags = Vec::new();
+        let tokens = tokenise(&text, base, &mut diags);
+        let (ast, constants) = parse(tokens.clone(), &mut sources, &options.target, &mut diags);
+        let mut later = Vec::new();
+        let (ast, sections) = place_sections(ast, options.split_pages, &options.target, &mut later);
+        let (ast, labels) = transform_labels(ast, &constants, &options.target, &mut later);
+        let image = assemble(ast, &options.target, &mut later);
+        if diagnostic::error_count(&diags) == 0 {
+            diags.extend(later);
+        }
+
+        Analysis {
+            uri: uri.to_string(),
+            text,
+            sources,
+            tokens,
+            diags,
+            labels,
+            image: Image { sections, ..image },
+        }
+    }
+
+    // whether a real span is in the document rather than something it included
+    fn in_document(&self, span: &Span) -> bool {
+        span.start <= self.text.len()
+    }
+
+    /// A `Location` for a span anywhere in the build.
+    pub fn location(&self, span: &Span) -> Value {
+        let (real, _) = self.sources.resolve(span);
+        let (file, local) = self.sources.file(real.start);
+        let uri = match self.in_document(&real) {
+            true => self.uri.clone(),
+            false => path_to_uri(Path::new(&file.name)),
+        };
+        json!({ "uri": uri, "range": range(&file.src, &(local..local + real.len())) })
+    }
+
+    /// The diagnostics as LSP `Diagnostic`s. Anything in an included file is shown
+    /// on the `.include` that pulled it in, with a link to where it really is.
+    pub fn diagnostics(&self) -> Vec<Value> {
+        self.diags
+            .iter()
+            .map(|diag| {
+                let (real, trace) = self.sources.resolve(&diag.span);
+                let mut message = diag.message.clone();
+                let mut related = Vec::new();
+                let span = match self.sources.include_chain(&real).last() {
+                    Some((name, from)) if !self.in_document(&real) => {
+                        message = format!("in `{}`: {}", name, message);
+                        let here = diag.label.clone().unwrap_or_else(|| diag.message.clone());
+                        related.push((real.clone(), here));
+                        from.clone()
+                    }
+                    _ => real,
+                };
+                message.extend(diag.label.iter().map(|l| format!("\n{}", l)));
+                message.extend(diag.notes.iter().map(|n| format!("\nnote: {}", n)));
+                related.extend(diag.secondary.iter().cloned());
+                related.extend(trace.into_iter().map(|(label, span)| (span, label)));
+
+                let severity = match diag.level {
+                    Level::Error => 1,
+                    Level::Warning => 2,
+                };
+                let related: Vec<_> = related
+                    .iter()
+                    .map(|(span, label)| json!({ "location": self.location(span), "message": label }))
+                    .collect();
+                json!({
+                    "range": range(&self.text, &span),
+                    "severity": severity,
+                    "source": "carbon-assembler",
+                    "message": message,
+                    "relatedInformation": related,
+                })
+            })
+            .collect()
+    }
+
+    pub fn token_at(&self, offset: usize) -> Option<&Spanned<Token>> {
+        self.tokens
+            .iter()
+            .find(|t| t.span.start <= offset && offset <= t.span.end)
+    }
+
+    /// The label named at an offset in the document, and where its name is.
+    pub fn label_at(&self, offset: usize) -> Option<(&str, Span)> {
+        let (name, span) = label_name(self.token_at(offset)?)?;
+        self.labels.contains_key(name).then_some((name, span))
+    }
+
+    /// Every place a label is named in the build, each with whether it's the
+    /// definition. Macro bodies are searched as written, so a label local to a
+    /// macro is found wherever one of the same name is.
+    pub fn references(&self, label: &str) -> Vec<(Span, bool)> {
+        let mut ret = Vec::new();
+        for (base, file) in self.sources.files() {
+            for tok in tokenise(&file.src, base, &mut Vec::new()).iter() {
+                if let Some((name, span)) = label_name(tok) {
+                    if name == label {
+                        ret.push((span, matches!(tok.node, Token::Label(_))));
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Bytes used out of those available, at the end of the line of each page
+    /// marker and `.section` in the document.
+    pub fn page_usage(&self) -> Vec<(usize, String)> {
+        let image = &self.image;
+        let usage = |page: usize, pages: usize| {
+            let used: usize = image.page_usage.iter().skip(page).take(pages).sum();
+            format!("{}/{} bytes", used, pages * image.page_size)
+        };
+        let mut ret: Vec<(usize, String)> = Vec::new();
+        for s in image.sections.iter() {
+            let (real, _) = self.sources.resolve(&s.span);
+            if self.in_document(&real) {
+                ret.push((real.start, usage(s.page, s.pages)));
+            }
+        }
+        for tok in self.tokens.iter() {
+            if let Token::PageLabel(page) = tok.node {
+                if page < image.page_count {
+                    ret.push((tok.span.start, usage(page, 1)));
+                }
+            }
+        }
+        // one hint to a line, at its end
+        let mut ret: Vec<_> = ret
+            .into_iter()
+            .map(|(offset, hint)| {
+                let end = self.text[offset..]
+                    .find(['\r', '\n'])
+                    .map_or(self.text.len(), |p| offset + p);
+                (end, hint)
+            })
+            .collect();
+        ret.sort_by_key(|(end, _)| *end);
+        ret.dedup_by_key(|(end, _)| *end);
+        ret
+    }
+}
+
+// the label a token names if it names one, and where in the token the name is
+fn label_name(tok: &Spanned<Token>) -> Option<(&str, Span)> {
+    let Span { start, end } = tok.span.clone();
+    match &tok.node {
+        Token::Label(l) => Some((l, start + 1..end)),
+        Token::LabelDeref(l) => Some((l, start + 1..end - 1)),
+        Token::Ident(l) => Some((l, start..end)),
+        _ => None,
+    }
+}
+
+/// The bits of an instruction, with letters for the ones its operands fill in,
+/// e.g. `10010ccc aaaaa000` for `BRC`.
+pub fn encoding(def: &InstrDef) -> String {
+    let mut ret = format!("{:08b}", def.opcode)[..5].to_string();
+    ret.push_str(match def.operands.first() {
+        Some(Operand::Reg) => "rrr",
+        Some(Operand::Cond) => "ccc",
+        _ => "000",
+    });
+    for operand in def.operands {
+        match operand {
+            Operand::Addr => ret.push_str(" aaaaa000"),
+            Operand::Imm => ret.push_str(" iiiiiiii"),
+            _ => (),
+        }
+    }
+    ret
+}
@@ -0,0 +1,373 @@
+//! A language server for carbon assembly, spoken over a pair of streams (normally
+//! stdin and stdout). Documents are synced whole and assembled again on every
+//! change; requests are answered from the last run.
+
+mod analysis;
+mod protocol;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+
+use crate::frontend::lexer::Token;
+use crate::instr::CarbonConds;
+use crate::isa::Operand;
+use crate::Options;
+use analysis::{encoding, Analysis};
+use protocol::{notification, offset, position, range, read_message, response, write_message};
+
+const CONDS: [CarbonConds; 8] = [
+    CarbonConds::Even,
+    CarbonConds::Jmp,
+    CarbonConds::Eq,
+    CarbonConds::Neq,
+    CarbonConds::Lt,
+    CarbonConds::Gt,
+    CarbonConds::Gteq,
+    CarbonConds::Lteq,
+];
+
+// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// `CompletionItemKind`s
+const KIND_VARIABLE: u32 = 6;
+const KIND_KEYWORD: u32 = 14;
+const KIND_REFERENCE: u32 = 18;
+const KIND_ENUM_MEMBER: u32 = 20;
+
+struct Server {
+    options: Options,
+    include_paths: Vec<PathBuf>,
+    docs: HashMap<String, Analysis>,
+}
+
+/// Answers requests from `input` on `output` until the client sends `exit` or
+/// closes the stream. Every document is assembled with `options`, searching
+/// `include_paths` for `.include`s as the command line would.
+pub fn serve(
+    mut input: impl BufRead,
+    mut output: impl Write,
+    options: Options,
+    include_paths: Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut server = Server {
+        options,
+        include_paths,
+        docs: HashMap::new(),
+    };
+    while let Some(msg) = read_message(&mut input)? {
+        let method = msg["method"].as_str().unwrap_or_default();
+        let params = &msg["params"];
+        match msg.get("id") {
+            // a request; anything with an id and no method is a response, and we
+            // never send requests
+            Some(id) if msg.get("method").is_some() => {
+                let result = server.request(method, params);
+                write_message(&mut output, &response(id.clone(), result))?;
+            }
+            Some(_) => (),
+            None if method == "exit" => break,
+            None => {
+                if let Some(uri) = server.notify(method, params) {
+                    let diagnostics = match server.docs.get(&uri) {
+                        Some(doc) => doc.diagnostics(),
+                        None => vec![],
+                    };
+                    let params = json!({ "uri": uri, "diagnostics": diagnostics });
+                    let msg = notification("textDocument/publishDiagnostics", params);
+                    write_message(&mut output, &msg)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    fn request(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        if method == "initialize" {
+            return Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 1 },
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["["] },
+                    "inlayHintProvider": true,
+                },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            }));
+        }
+        if method == "shutdown" {
+            return Ok(Value::Null);
+        }
+
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let doc = self.docs.get(uri);
+        let at = doc.and_then(|doc| Some((doc, offset(&doc.text, &params["position"])?)));
+        let invalid = || (INVALID_PARAMS, format!("`{}` isn't open", uri));
+        match method {
+            "textDocument/definition" => {
+                let (doc, at) = at.ok_or_else(invalid)?;
+                Ok(match doc.label_at(at) {
+                    Some((name, _)) => doc.location(&doc.labels[name].span),
+                    None => Value::Null,
+                })
+            }
+            "textDocument/references" => {
+                let (doc, at) = at.ok_or_else(invalid)?;
+                let Some((name, _)) = doc.label_at(at) else {
+                    return Ok(Value::Null);
+                };
+                let declaration = params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(true);
+                let refs: Vec<_> = doc
+                    .references(name)
+                    .into_iter()
+                    .filter(|(_, def)| declaration || !def)
+                    .map(|(span, _)| doc.location(&span))
+                    .collect();
+                Ok(json!(refs))
+            }
+            "textDocument/hover" => {
+                let (doc, at) = at.ok_or_else(invalid)?;
+                Ok(self.hover(doc, at).unwrap_or(Value::Null))
+            }
+            "textDocument/completion" => {
+                let (doc, at) = at.ok_or_else(invalid)?;
+                Ok(json!(self.completion(doc, at)))
+            }
+            "textDocument/inlayHint" => {
+                let doc = doc.ok_or_else(invalid)?;
+                let hints: Vec<_> = doc
+                    .page_usage()
+                    .into_iter()
+                    .map(|(at, label)| {
+                        json!({
+                            "position": position(&doc.text, at),
+                            "label": label,
+                            "paddingLeft": true,
+                        })
+                    })
+                    .collect();
+                Ok(json!(hints))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("`{}` isn't supported", method))),
+        }
+    }
+
+    // returns the document whose diagnostics need publishing again, if any
+    fn notify(&mut self, method: &str, params: &Value) -> Option<String> {
+        let uri = params["textDocument"]["uri"].as_str()?.to_string();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str()?,
+            // sync is always full, so the last change has the whole document
+            "textDocument/didChange" => {
+                params["contentChanges"].as_array()?.last()?["text"].as_str()?
+            }
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                return Some(uri);
+            }
+            _ => return None,
+        };
+        let doc = Analysis::new(&uri, text.to_string(), &self.options, &self.include_paths);
+        self.docs.insert(uri.clone(), doc);
+        Some(uri)
+    }
+
+    fn hover(&self, doc: &Analysis, at: usize) -> Option<Value> {
+        let tok = doc.token_at(at)?;
+        let text = match &tok.node {
+            Token::Instr(i) => {
+                let def = self.options.target.instr(*i)?;
+                let size = match def.size() {
+                    1 => "1 byte".to_string(),
+                    n => format!("{} bytes", n),
+                };
+                format!(
+                    "```\n{}\n```\n`{}`, {}\n\n{}",
+                    def.usage(),
+                    encoding(def),
+                    size,
+                    def.description
+                )
+            }
+            _ => {
+                let (name, _) = doc.label_at(at)?;
+                let def = &doc.labels[name];
+                format!(
+                    "```\n.{}\n```\npage {}, offset {}; jumps to it encode {}",
+                    name,
+                    def.page,
+                    def.pc + 1,
+                    def.pc.rem_euclid(doc.image.page_size as isize)
+                )
+            }
+        };
+        Some(json!({
+            "contents": { "kind": "markdown", "value": text },
+            "range": range(&doc.text, &tok.span),
+        }))
+    }
+
+    // what could go where the cursor is, going by what's before it on the line
+    fn completion(&self, doc: &Analysis, at: usize) -> Vec<Value> {
+        let line = &doc.text[doc.text[..at].rfind('\n').map_or(0, |p| p + 1)..at];
+        if line.contains('#') || line.contains("//") {
+            return vec![];
+        }
+        // the word being typed doesn't count, and nor do labels and page markers
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if !line.ends_with(char::is_whitespace) {
+            words.pop();
+        }
+        words.retain(|w| !w.starts_with('.') && !w.starts_with('>'));
+
+        let target = &self.options.target;
+        let operand = match words.first() {
+            None => {
+                return target
+                    .isa
+                    .iter()
+                    .map(|def| {
+                        json!({
+                            "label": def.mnemonic,
+                            "kind": KIND_KEYWORD,
+                            "detail": def.usage(),
+                            "documentation": def.description,
+                        })
+                    })
+                    .collect()
+            }
+            Some(word) => target
+                .by_mnemonic(word)
+                .map(|def| def.operands.get(words.len() - 1).copied()),
+        };
+
+        let registers =
+            || (0..8).map(|r| json!({ "label": format!("r{}", r), "kind": KIND_VARIABLE }));
+        let conds = || {
+            CONDS
+                .iter()
+                .map(|c| json!({ "label": c.to_string(), "kind": KIND_ENUM_MEMBER }))
+        };
+        let labels = || {
+            let mut labels: Vec<_> = doc
+                .labels
+                .iter()
+                // labels local to a macro expansion can't be named from outside it
+                .filter(|(name, _)| !name.contains('@'))
+                .collect();
+            labels.sort_by_key(|(name, _)| name.to_string());
+            labels.into_iter().map(|(name, def)| {
+                json!({
+                    "label": name,
+                    "kind": KIND_REFERENCE,
+                    "detail": format!("page {}, offset {}", def.page, def.pc + 1),
+                })
+            })
+        };
+        match operand {
+            Some(Some(Operand::Reg)) => registers().collect(),
+            Some(Some(Operand::Cond)) => conds().collect(),
+            Some(Some(Operand::Addr | Operand::Imm)) => labels().collect(),
+            Some(None) => vec![],
+            // a directive or macro call could take anything
+            None => labels().chain(registers()).chain(conds()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // runs a whole session, `exit` included, and collects what the server sent back
+    fn session(msgs: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for msg in msgs {
+            write_message(&mut input, msg).unwrap();
+        }
+        write_message(&mut input, &notification("exit", Value::Null)).unwrap();
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output, Options::default(), vec![]).unwrap();
+        let mut output = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+
+    fn request(id: i64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    #[test]
+    fn round_trip() {
+        let uri = "file:///test.carbon";
+        let doc = json!({ "uri": uri });
+        let replies = session(&[
+            request(1, "initialize", json!({})),
+            notification(
+                "textDocument/didOpen",
+                json!({ "textDocument": { "uri": uri, "text": "BRC JMP [strat]\n.start\nHLT" } }),
+            ),
+            notification(
+                "textDocument/didChange",
+                json!({
+                    "textDocument": doc,
+                    "contentChanges": [{ "text": "BRC JMP [start]\n.start\nHLT" }],
+                }),
+            ),
+            request(
+                2,
+                "textDocument/definition",
+                json!({ "textDocument": doc, "position": { "line": 0, "character": 10 } }),
+            ),
+            request(3, "textDocument/formatting", json!({ "textDocument": doc })),
+            request(4, "shutdown", Value::Null),
+        ]);
+        assert_eq!(replies.len(), 6);
+
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+
+        // the misspelt label, then nothing once it's fixed
+        assert_eq!(replies[1]["method"], "textDocument/publishDiagnostics");
+        let diags = replies[1]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0]["severity"], 1);
+        assert_eq!(
+            diags[0]["range"],
+            json!({
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": 15 },
+            })
+        );
+        assert_eq!(replies[2]["params"]["diagnostics"], json!([]));
+
+        assert_eq!(replies[3]["id"], 2);
+        assert_eq!(
+            replies[3]["result"],
+            json!({
+                "uri": uri,
+                "range": {
+                    "start": { "line": 1, "character": 0 },
+                    "end": { "line": 1, "character": 6 },
+                },
+            })
+        );
+
+        assert_eq!(replies[4]["id"], 3);
+        assert_eq!(replies[4]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies[5], response(json!(4), Ok(Value::Null)));
+    }
+}
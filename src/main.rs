@@ -1,43 +1,394 @@
-mod backend;
-mod frontend;
-mod instr;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
-use std::io::Write;
-
-use clap::Parser;
-
-use crate::backend::assembler::PageOutput;
+use carbon_assembler::{
+    backend::{
+        disassembler,
+        output::{self, ImageWriter},
+        schematic::{Axis, Placement, SchematicWriter},
+    },
+    diagnostic,
+    emulator::Emulator,
+    isa::Target,
+    lsp, Diagnostic, Image, Options, SourceFile, SourceMap,
+};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(name = "Input file")]
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(name = "Input file", required = true)]
+    input_file: Option<String>,
+
+    /// Defaults to `out` with the format's extension
+    #[arg(short, long, name = "Output file")]
+    output: Option<String>,
+
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(flatten)]
+    schem: SchemArgs,
+
+    /// Also write each page to its own file, named after the output file with
+    /// `.page<n>` before the extension; needs `--format logisim`
+    #[arg(long)]
+    logisim_pages: bool,
+
+    /// Print which page each section was placed on
+    #[arg(long)]
+    sections: bool,
+
+    /// Also write a listing of each line's address and encoding, named after the
+    /// output file with a `.lst` extension
+    #[arg(long)]
+    listing: bool,
+
+    /// Write the symbol table to this file; as JSON if it ends in `.json`, otherwise
+    /// as `name page offset` text
+    #[arg(long, name = "Symbol file")]
+    symbols: Option<PathBuf>,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+// options that affect how source is assembled, shared by everything that assembles
+#[derive(clap::Args)]
+struct BuildArgs {
+    /// Extra directory to search for `.include`d files; may be repeated
+    #[arg(short = 'I', long = "include-path", name = "Directory")]
+    include_paths: Vec<PathBuf>,
+
+    /// Spread sections that don't fit in one page over consecutive pages
+    #[arg(long)]
+    split_pages: bool,
+
+    #[command(flatten)]
+    target: TargetArg,
+}
+
+#[derive(clap::Args)]
+struct TargetArg {
+    /// Hardware to assemble for: a built in target such as `carbon`, or a `.toml`
+    /// profile that changes one
+    #[arg(long, default_value = "carbon", value_parser = Target::find, name = "Target")]
+    target: Target,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// `// PAGE n` headers and a line of binary per byte
+    Text,
+    /// The raw ROM image, every page included
+    Bin,
+    /// Hex dump with addresses
+    Hex,
+    /// Intel HEX records
+    Ihex,
+    /// Sponge schematic with a block per bit, for pasting with WorldEdit
+    Schem,
+    /// Logisim-evolution `v2.0 raw` memory image
+    Logisim,
+}
+
+impl Format {
+    fn writer(self, schem: SchemArgs) -> Box<dyn ImageWriter> {
+        match self {
+            Format::Text => Box::new(output::TextWriter),
+            Format::Bin => Box::new(output::BinWriter),
+            Format::Hex => Box::new(output::HexWriter),
+            Format::Ihex => Box::new(output::IntelHexWriter),
+            Format::Logisim => Box::new(output::LogisimWriter { page: None }),
+            Format::Schem => {
+                let [bit, byte, page] = [0, 1, 2].map(|n| Placement {
+                    axis: schem.schem_axes[n],
+                    spacing: schem.schem_spacing[n],
+                });
+                let writer = SchematicWriter {
+                    zero: schem.schem_zero,
+                    one: schem.schem_one,
+                    bit,
+                    byte,
+                    page,
+                };
+                if let Err(e) = writer.validate() {
+                    eprintln!("error: {}", e);
+                    exit(1);
+                }
+                Box::new(writer)
+            }
+        }
+    }
+}
+
+// how `--format schem` lays out the ROM
+#[derive(clap::Args)]
+struct SchemArgs {
+    /// Block for 0 bits in a schematic
+    #[arg(long, default_value = "minecraft:glass", name = "Zero block")]
+    schem_zero: String,
+
+    /// Block for 1 bits in a schematic
+    #[arg(long, default_value = "minecraft:redstone_block", name = "One block")]
+    schem_one: String,
+
+    /// Axes that bits, bytes and pages run along in a schematic, such as `yxz`
+    #[arg(long, default_value = "yxz", value_parser = parse_axes, name = "Axes")]
+    schem_axes: [Axis; 3],
+
+    /// Blocks from one bit, byte and page to the next in a schematic, such as `2,2,4`
+    #[arg(long, default_value = "2,2,2", value_parser = parse_spacing, name = "Spacing")]
+    schem_spacing: [usize; 3],
+}
+
+fn parse_axes(s: &str) -> Result<[Axis; 3], String> {
+    let axes: Vec<Axis> = s
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            'x' => Ok(Axis::X),
+            'y' => Ok(Axis::Y),
+            'z' => Ok(Axis::Z),
+            c => Err(format!("`{}` is not an axis; expected x, y or z", c)),
+        })
+        .collect::<Result<_, _>>()?;
+    axes.try_into()
+        .map_err(|_| "expected three axes, one each for bits, bytes and pages".to_string())
+}
+
+fn parse_spacing(s: &str) -> Result<[usize; 3], String> {
+    let spacing: Vec<usize> = s
+        .split(',')
+        .map(|n| {
+            n.trim()
+                .parse()
+                .map_err(|_| format!("`{}` is not a number", n))
+        })
+        .collect::<Result<_, _>>()?;
+    spacing
+        .try_into()
+        .map_err(|_| "expected three numbers, for bits, bytes and pages".to_string())
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble a program and run it on the emulator
+    Run {
+        #[arg(name = "Input file")]
+        input_file: String,
+
+        #[arg(long, default_value_t = 100_000)]
+        max_cycles: usize,
 
-    #[arg(short, long, name = "Output file", default_value_t = String::from("out.b"))]
-    output: String,
+        #[command(flatten)]
+        build: BuildArgs,
+    },
+    /// Turn a `// PAGE` dump or raw binary back into assembly
+    Disassemble {
+        #[arg(name = "Input file")]
+        input_file: String,
+
+        #[arg(short, long, name = "Output file")]
+        output: Option<String>,
+
+        #[command(flatten)]
+        target: TargetArg,
+    },
+    /// Run a language server over stdin and stdout, for editors
+    Lsp {
+        #[command(flatten)]
+        build: BuildArgs,
+    },
+}
+
+fn report(sources: &SourceMap, diags: &[Diagnostic]) {
+    for diag in diags.iter() {
+        eprintln!("{}\n", sources.render(diag));
+    }
+    if let Some(summary) = diagnostic::summary(diags) {
+        eprintln!("{}", summary);
+    }
+}
+
+// reads an input file, exiting with an error if it can't be
+fn read_input(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("error: can't read `{}`: {}", path, e);
+        exit(1)
+    })
+}
+
+// writes an output file, exiting with an error if it can't be
+fn write_output(path: impl AsRef<Path>, data: impl AsRef<[u8]>) {
+    let path = path.as_ref();
+    std::fs::write(path, data).unwrap_or_else(|e| {
+        eprintln!("error: can't write `{}`: {}", path.display(), e);
+        exit(1)
+    })
+}
+
+// writes an image in some format, exiting with an error if it can't be
+fn write_image(path: impl AsRef<Path>, writer: &dyn ImageWriter, image: &Image) {
+    let mut data = Vec::new();
+    // writing to memory can't fail
+    writer.write(image, &mut data).unwrap();
+    write_output(path, data);
+}
+
+// assembles a file, printing any diagnostics and exiting if there were errors
+fn build(path: String, args: BuildArgs) -> (Image, SourceMap) {
+    let src = String::from_utf8(read_input(&path)).unwrap_or_else(|_| {
+        eprintln!("error: `{}` isn't UTF-8 text", path);
+        exit(1)
+    });
+    let mut sources = SourceMap::new();
+    sources.include_paths = args.include_paths;
+    let options = Options {
+        split_pages: args.split_pages,
+        target: args.target.target,
+    };
+    match carbon_assembler::assemble_file(&mut sources, SourceFile::new(path, src), &options) {
+        Ok(image) => {
+            report(&sources, &image.warnings);
+            (image, sources)
+        }
+        Err(diags) => {
+            report(&sources, &diags);
+            exit(1)
+        }
+    }
+}
+
+fn print_sections(image: &Image) {
+    let width = image
+        .sections
+        .iter()
+        .map(|s| s.name.len())
+        .max()
+        .unwrap_or(0);
+    for s in image.sections.iter() {
+        let pages = s.page..s.page + s.pages;
+        let used: usize = image.page_usage[pages.clone()].iter().sum();
+        let pages = match s.pages {
+            1 => format!("page {:2}", s.page),
+            _ => format!("pages {}-{}", pages.start, pages.end - 1),
+        };
+        println!(
+            "{:width$}  {:8}  {:3}/{} bytes{}",
+            s.name,
+            pages,
+            used,
+            image.page_size * s.pages,
+            if s.pinned { "  (pinned)" } else { "" }
+        );
+    }
+}
+
+fn disassemble(path: String, output: Option<String>, target: &Target) {
+    let data = read_input(&path);
+    let image = if disassembler::is_page_dump(&data) {
+        let src = String::from_utf8(data).unwrap();
+        let mut diags = Vec::new();
+        let image = disassembler::parse_page_dump(&src, target, &mut diags);
+        let mut sources = SourceMap::new();
+        sources.add_file(SourceFile::new(path, src));
+        report(&sources, &diags);
+        if diagnostic::error_count(&diags) > 0 {
+            exit(1);
+        }
+        image
+    } else {
+        data
+    };
+    let src = disassembler::disassemble(&image, target);
+    match output {
+        Some(path) => write_output(path, src),
+        None => print!("{}", src),
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let src = std::fs::read_to_string(args.input_file).unwrap();
-    let toks = frontend::lexer::tokenise(&src);
-    let mut ast = frontend::parser::parse(toks);
-    ast = frontend::parser::transform_labels(ast);
-    let asm = backend::assembler::assemble(ast);
-    let out_file = &mut std::fs::File::create(args.output).unwrap();
-    write!(out_file, "// PAGE 0").unwrap();
-    let mut ctr = 0;
-    for word in asm.iter() {
-        if ctr % 32 == 0 && ctr / 32 != 0 {
-            write!(out_file, "\n// PAGE {}", ctr / 32).unwrap();
-        }
-
-        match word {
-            PageOutput::Lit(n) => {
-                ctr += 1;
-                write!(out_file, "\n{:08b}", n).unwrap();
+    // clap can only make one argument require another, not a particular value of it
+    if args.logisim_pages && !matches!(args.format, Format::Logisim) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "`--logisim-pages` can only be used with `--format logisim`",
+            )
+            .exit();
+    }
+    match args.command {
+        Some(Command::Run {
+            input_file,
+            max_cycles,
+            build: build_args,
+        }) => {
+            let target = build_args.target.target.clone();
+            let mut emu = Emulator::new(build(input_file, build_args).0.bytes(), &target);
+            let res = emu.run(max_cycles);
+            println!("{}", emu);
+            if let Err(e) = res {
+                eprintln!("error: {}", e);
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Disassemble {
+            input_file,
+            output,
+            target,
+        }) => {
+            return disassemble(input_file, output, &target.target);
+        }
+        Some(Command::Lsp { build }) => {
+            let options = Options {
+                split_pages: build.split_pages,
+                target: build.target.target,
+            };
+            let (input, output) = (io::stdin().lock(), io::stdout().lock());
+            if let Err(e) = lsp::serve(input, output, options, build.include_paths) {
+                eprintln!("error: {}", e);
+                exit(1);
             }
-            PageOutput::Comment(n) => write!(out_file, " {}", *n).unwrap(),
+            return;
+        }
+        None => (),
+    }
+
+    let (image, sources) = build(args.input_file.unwrap(), args.build);
+    if args.sections {
+        print_sections(&image);
+    }
+    let writer = args.format.writer(args.schem);
+    let output = args
+        .output
+        .unwrap_or_else(|| format!("out.{}", writer.extension()));
+    if args.listing {
+        let path = Path::new(&output).with_extension("lst");
+        write_output(path, carbon_assembler::listing(&image, &sources));
+    }
+    if let Some(path) = args.symbols {
+        let symbols = match path.extension().is_some_and(|e| e == "json") {
+            true => carbon_assembler::symbols_json(&image, &sources),
+            false => carbon_assembler::symbols_text(&image),
         };
+        write_output(path, symbols);
+    }
+    if args.logisim_pages {
+        let path = Path::new(&output);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+        for page in 0..image.page_count {
+            let path = path.with_file_name(format!("{}.page{}.{}", stem, page, ext));
+            write_image(path, &output::LogisimWriter { page: Some(page) }, &image);
+        }
     }
+    write_image(output, writer.as_ref(), &image);
 }